@@ -1,46 +1,108 @@
-use std::time::Duration;
+use std::{sync::Arc, time::Duration};
 
 use color_eyre::eyre::Result;
 use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
-use ratatui::{layout::Layout, widgets::{Block, Paragraph}, DefaultTerminal, Frame};
-use tokio::sync::mpsc;
-use crate::{download_component::DownloadComponent, http_tester::HttpTester, ping_component::PingComponent, servers::Servers, services::{HttpTestService, HttpTestState}, upload_component::UploadComponent};
+use ratatui::{layout::{Layout, Rect}, style::{Color, Style, Stylize}, text::{Line, Text}, widgets::{Block, Paragraph, Tabs, Wrap}, DefaultTerminal, Frame};
+use crate::{backend::{BackendKind, SpeedTestBackend}, cli::Cli, custom_tester::CustomTester, download_component::DownloadComponent, http_tester::{HttpTester, TransferSettings}, icmp_tester::IcmpTester, iperf_tester::IperfTester, librespeed_tester::LibreSpeedTester, picker_component::PickerComponent, ping_component::PingComponent, selection_component::SelectionComponent, server_selection::ServerSelection, servers::{ClientConfig, Server, Servers}, tcp_tester::TcpTester, services::{HttpTestService, HttpTestState}, timing_component::TimingComponent, traceroute::{Traceroute, TracerouteRun}, traceroute_component::TracerouteComponent, udp_component::UdpComponent, udp_tester::UdpTester, upload_component::UploadComponent};
+
+/// Screens switched between with `Tab`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AppTab {
+    Test,
+    Path,
+}
+
+impl AppTab {
+    pub fn next(&self) -> Self {
+        match self {
+            AppTab::Test => AppTab::Path,
+            AppTab::Path => AppTab::Test,
+        }
+    }
+}
 
 pub struct App {
     running: bool,
+    cli: Cli,
     servers: Servers,
+    server: Server,
+    server_error: Option<String>,
+    selection: Option<ServerSelection>,
+    client: Option<ClientConfig>,
+    target: String,
+    backend_kind: BackendKind,
     test_service: HttpTestService,
     ping_component: PingComponent,
     download_component: DownloadComponent,
     upload_component: UploadComponent,
+    timing_component: TimingComponent,
+    udp_component: UdpComponent,
+    selection_component: SelectionComponent,
+    picker_component: PickerComponent,
+    traceroute_component: TracerouteComponent,
+    traceroute: Option<TracerouteRun>,
+    tab: AppTab,
+    show_details: bool,
+    show_picker: bool,
 }
 
 impl App {
-    pub fn new() -> Self {
+    pub fn new(cli: Cli) -> Self {
         Self {
             running: true,
-            servers: Servers::default(),
-            test_service: HttpTestService::new(HttpTester::default()),
+            backend_kind: cli.backend,
+            cli,
+            servers: Servers::new(),
+            server: Server::default(),
+            server_error: None,
+            selection: None,
+            client: None,
+            target: String::new(),
+            test_service: HttpTestService::new(Arc::new(HttpTester::default())),
             ping_component: PingComponent::default(),
             download_component: DownloadComponent::default(),
             upload_component: UploadComponent::default(),
+            timing_component: TimingComponent::default(),
+            udp_component: UdpComponent::default(),
+            selection_component: SelectionComponent::default(),
+            picker_component: PickerComponent::default(),
+            traceroute_component: TracerouteComponent::default(),
+            traceroute: None,
+            tab: AppTab::Test,
+            show_details: false,
+            show_picker: false,
         }
     }
 
     pub async fn run(mut self, mut terminal: DefaultTerminal) -> Result<()> {
         self.running = true;
-        let server_update_result = self.servers.update_servers().await;
-        match server_update_result {
-            Ok(_) => {
-            }
-            Err(e) => {
-                eprintln!("Failed to update servers: {}", e);
-                return Err(e.into());
+        if let Some(url) = self.cli.librespeed.clone() {
+            self.target = url.clone();
+            self.test_service.set_tester(Arc::new(LibreSpeedTester::new(url.as_str(), self.transfer_settings())));
+        } else if let Some(host) = self.cli.iperf.clone() {
+            let tester = IperfTester::new(host.as_str(), self.transfer_settings());
+            self.target = tester.host.clone();
+            self.test_service.set_tester(Arc::new(tester));
+        } else if !self.cli.download_urls.is_empty() {
+            let tester = self.build_custom_backend();
+            self.target = tester.latency_url.clone();
+            self.test_service.set_tester(Arc::new(tester));
+        } else if let Some(host) = self.cli.server.clone() {
+            self.server = Server::from_host(host.as_str());
+            self.target = host;
+            self.test_service.set_tester(self.build_backend(&self.server));
+        } else {
+            match self.servers.update_servers().await {
+                Ok(_) => self.use_server_list().await,
+                Err(e) => self.server_error = Some(e.to_string()),
             }
         }
-        let current_server = self.servers.get_servers()[0].clone(); 
-        let url = format!("http://{}", current_server.host);
-        self.test_service.set_tester(HttpTester::new(url.as_str()));
+
+        self.test_service.set_icmp_tester(Some(IcmpTester::new(IcmpTester::host_of(self.target.as_str()).as_str())));
+
+        // `serve` answers the UDP test on the port it serves HTTP on
+        let udp_host = self.cli.udp.clone().or_else(|| self.cli.server.clone());
+        self.test_service.set_udp_tester(udp_host.map(|host| UdpTester::new(host.as_str())));
 
         while self.running {
 
@@ -48,25 +110,80 @@ impl App {
                 if self.test_service.get_state().clone() == HttpTestState::MeasuringLatency {
                     self.ping_component.set_active(true);
                 }
+                if self.test_service.get_state().clone() == HttpTestState::MeasuringUdp {
+                    self.ping_component.set_active(false);
+                    self.udp_component.set_active(true);
+                    let new_ping_measurment = self.test_service.get_ping_results().clone();
+                    self.timing_component.set_timings(new_ping_measurment.timings.clone());
+                    self.ping_component.set_ping_measurement(new_ping_measurment);
+                    self.ping_component.set_icmp_measurement(self.test_service.get_icmp_results().cloned().map_err(str::to_string));
+                }
                 if self.test_service.get_state().clone() == HttpTestState::MeasuringDownload {
                     self.ping_component.set_active(false);
+                    self.udp_component.set_active(false);
+                    self.udp_component.set_udp_measurement(self.test_service.get_udp_results().cloned().map_err(str::to_string));
                     self.download_component.set_active(true);
                     let new_ping_measurment = self.test_service.get_ping_results().clone();
+                    if self.server.is_listed() && new_ping_measurment.total_measurments > 0 {
+                        self.picker_component.set_latency(self.server.id, new_ping_measurment.median);
+                    }
+                    self.timing_component.set_timings(new_ping_measurment.timings.clone());
                     self.ping_component.set_ping_measurement(new_ping_measurment);
+                    self.ping_component.set_icmp_measurement(self.test_service.get_icmp_results().cloned().map_err(str::to_string));
+                    self.download_component.set_progress(self.test_service.get_download_progress().cloned());
                 }
                 if  self.test_service.get_state().clone() == HttpTestState::MeasuringUpload {
                     self.download_component.set_active(false);
                     self.upload_component.set_active(true);
-                    let new_download_measurment = self.test_service.get_download_results().clone();
+                    let new_download_measurment = self.test_service.get_download_results().cloned().map_err(str::to_string);
+                    // A failed phase leaves no loaded latency worth grading
+                    self.ping_component.set_download_loaded(new_download_measurment.as_ref().map(|download| download.loaded_latency.clone()).unwrap_or_default());
                     self.download_component.set_download_measurement(new_download_measurment);
+                    self.upload_component.set_progress(self.test_service.get_upload_progress().cloned());
+                }
+                if self.test_service.get_state().clone() == HttpTestState::MeasuringResponsiveness {
+                    self.ping_component.set_active(true);
+                    self.download_component.set_active(true);
+                    self.download_component.set_progress(self.test_service.get_download_progress().cloned());
                 }
                 if self.test_service.get_state().clone() == HttpTestState::Finished {
+                    self.ping_component.set_active(false);
+                    self.download_component.set_active(false);
+                    self.ping_component.set_responsiveness(self.test_service.get_responsiveness_results().cloned().map_err(str::to_string));
                     self.upload_component.set_active(false);
-                    let new_upload_measurment = self.test_service.get_upload_results().clone();
+                    let new_upload_measurment = self.test_service.get_upload_results().cloned().map_err(str::to_string);
+                    self.ping_component.set_upload_loaded(new_upload_measurment.as_ref().map(|upload| upload.loaded_latency.clone()).unwrap_or_default());
                     self.upload_component.set_upload_measurement(new_upload_measurment);
                 }
             }
 
+            if let Some(selection) = &mut self.selection {
+                selection.update();
+                self.selection_component.set_candidates(selection.candidates.clone());
+                if selection.is_finished() {
+                    for candidate in &selection.candidates {
+                        if let Some(latency) = &candidate.latency
+                            && latency.total_measurments > 0 {
+                            self.picker_component.set_latency(candidate.server.id, latency.median);
+                        }
+                    }
+                    let best = selection.best().cloned();
+                    self.selection = None;
+                    if let Some(server) = best {
+                        self.use_server(server);
+                    }
+                }
+            }
+
+            if let Some(traceroute) = &mut self.traceroute {
+                while let Ok(update) = traceroute.updates.try_recv() {
+                    match update {
+                        Ok(hops) => self.traceroute_component.set_hops(hops),
+                        Err(e) => self.traceroute_component.set_error(e),
+                    }
+                }
+            }
+
             self.test_service.check_measurments().await;
             terminal.draw(|frame| self.render(frame))?;
             
@@ -77,24 +194,186 @@ impl App {
         Ok(())
     }
 
+    /// Whether the test runs against the speedtest.net server list rather
+    /// than a target given on the command line.
+    fn uses_server_list(&self) -> bool {
+        self.cli.librespeed.is_none() && self.cli.iperf.is_none() && self.cli.download_urls.is_empty()
+    }
+
+    /// Sorts the fetched server list by distance, tests against the
+    /// nearest server and starts picking the best one among the closest.
+    async fn use_server_list(&mut self) {
+        // Given coordinates make the lookup unnecessary; without a location
+        // the list stays in speedtest.net's order
+        let location = match (self.cli.lat, self.cli.lon) {
+            (Some(lat), Some(lon)) => Some((lat, lon)),
+            _ => {
+                self.client = Servers::fetch_client().await.ok().flatten();
+                self.client.as_ref().map(|client| (client.lat, client.lon))
+            }
+        };
+        if let Some((lat, lon)) = location {
+            self.servers.sort_by_distance(lat, lon);
+        }
+        self.picker_component.set_servers(self.servers.get_servers().clone());
+        self.picker_component.set_skipped(self.servers.get_skipped());
+        self.use_server(self.servers.get_servers()[0].clone());
+        let candidates = (self.cli.candidates as usize).min(self.servers.get_servers().len());
+        if candidates > 1 {
+            self.selection = Some(ServerSelection::start(&self.servers.get_servers()[..candidates]));
+        }
+    }
+
+    /// Whether a server is chosen and a test can start.
+    fn ready(&self) -> bool {
+        self.selection.is_none() && self.server_error.is_none()
+    }
+
+    /// Switches the test to `server` from the list.
+    fn use_server(&mut self, server: Server) {
+        self.target = server.host.clone();
+        self.timing_component.set_server(server.clone());
+        self.server = server;
+        self.test_service.set_tester(self.build_backend(&self.server));
+        self.test_service.set_icmp_tester(Some(IcmpTester::new(IcmpTester::host_of(self.target.as_str()).as_str())));
+    }
+
+    fn build_backend(&self, server: &Server) -> Arc<dyn SpeedTestBackend> {
+        match self.backend_kind {
+            BackendKind::Http => {
+                let url = format!("http://{}", server.host);
+                let mut tester = HttpTester::new(url.as_str());
+                tester.set_settings(self.transfer_settings());
+                // Listed servers that speak HTTPS may offer HTTP/2, which lets the
+                // responsiveness test probe its load connections
+                tester.set_tls_url(server.https_functional.then(|| format!("https://{}", server.host)));
+                Arc::new(tester)
+            }
+            BackendKind::Tcp => Arc::new(TcpTester::new(server.host.as_str(), self.transfer_settings())),
+        }
+    }
+
+    fn build_custom_backend(&self) -> CustomTester {
+        let mut tester = CustomTester::new(self.cli.download_urls.clone(), self.transfer_settings());
+        if let Some(url) = &self.cli.upload_url {
+            tester.set_upload(url.as_str(), self.cli.upload_method);
+        }
+        if let Some(url) = &self.cli.latency_url {
+            tester.set_latency_url(url.as_str());
+        }
+        tester.set_headers(self.cli.headers.clone());
+        tester
+    }
+
+    fn transfer_settings(&self) -> TransferSettings {
+        TransferSettings {
+            connections: self.cli.connections as usize,
+            test_duration: Duration::from_secs(self.cli.duration),
+            warmup: Duration::from_secs(self.cli.warmup),
+            trim_fraction: self.cli.trim as f64 / 100.0,
+        }
+    }
+
     fn render(&mut self, frame: &mut Frame) {
-        let chunks = Layout::default()
+        let screen = Layout::default()
             .direction(ratatui::layout::Direction::Vertical)
             .margin(1)
             .constraints([
-                ratatui::layout::Constraint::Length(6),
-                ratatui::layout::Constraint::Min(3),
-                ratatui::layout::Constraint::Min(3),
+                ratatui::layout::Constraint::Length(1),
+                ratatui::layout::Constraint::Min(0),
             ].as_ref())
             .split(frame.area());
-        frame.render_widget(&self.ping_component, chunks[0]);
-        frame.render_widget(&self.download_component, chunks[1]);
-        frame.render_widget(&self.upload_component, chunks[2]);
-        let url = self.servers.get_servers()[0].host.clone();
-        let p = Block::default().title(url.as_str()).borders(ratatui::widgets::Borders::ALL);
+        let tabs = Tabs::new(vec!["Test", "Path"])
+            .select(match self.tab {
+                AppTab::Test => 0,
+                AppTab::Path => 1,
+            })
+            .highlight_style(Style::default().fg(Color::Green).bold());
+        frame.render_widget(tabs, screen[0]);
+        if self.show_picker {
+            frame.render_widget(&self.picker_component, screen[1]);
+        } else {
+            match self.tab {
+                AppTab::Test => self.render_test(frame, screen[1]),
+                AppTab::Path => frame.render_widget(&self.traceroute_component, screen[1]),
+            }
+        }
+
+        let target = if self.uses_server_list() { self.server.label() } else { self.target.clone() };
+        let mut title = format!("{} [{}]", target, self.test_service.get_tester().name());
+        if self.uses_server_list() && let Some(distance) = self.server.distance {
+            title = format!("{} {:.0} km", title, distance);
+        }
+        if let Some(info) = self.test_service.get_client_info() {
+            title = format!("{} - {}", title, info);
+        } else if let Some(client) = &self.client {
+            title = format!("{} - {}", title, client.describe());
+        }
+        let mut p = Block::default().title(title.as_str()).borders(ratatui::widgets::Borders::ALL);
+        if self.uses_server_list() {
+            let skipped = self.servers.get_skipped();
+            let mut problems = Vec::new();
+            if skipped > 0 {
+                problems.push(format!("{} malformed servers skipped", skipped));
+            }
+            if let Some(reason) = self.servers.get_truncated() {
+                problems.push(format!("server list cut short: {}", reason));
+            }
+            if !problems.is_empty() {
+                p = p.title(Line::from(problems.join(", ")).yellow().right_aligned());
+            }
+        }
         frame.render_widget(p, frame.area());
     }
 
+    fn render_test(&self, frame: &mut Frame, area: Rect) {
+        if let Some(error) = &self.server_error {
+            let message = Text::from(vec![
+                Line::from(format!("Failed to load the speedtest.net server list: {}", error)).red().bold(),
+                Line::from("Check your connection, or test against a server of your own with --server, --librespeed, --download-url or --iperf."),
+            ]);
+            let paragraph = Paragraph::new(message)
+                .block(Block::bordered().border_style(Style::default().fg(Color::Red)))
+                .alignment(ratatui::layout::Alignment::Center)
+                .wrap(Wrap { trim: true });
+            frame.render_widget(paragraph, area);
+            return;
+        }
+        // The test starts once the server is chosen
+        if self.selection.is_some() {
+            frame.render_widget(&self.selection_component, area);
+            return;
+        }
+        let details_height = if self.show_details { 10 } else { 0 };
+        let chunks = Layout::default()
+            .direction(ratatui::layout::Direction::Vertical)
+            .constraints([
+                ratatui::layout::Constraint::Length(13),
+                ratatui::layout::Constraint::Length(details_height),
+                ratatui::layout::Constraint::Min(3),
+                ratatui::layout::Constraint::Min(3),
+            ].as_ref())
+            .split(area);
+        if self.test_service.get_udp_tester().is_some() {
+            let top = Layout::default()
+                .direction(ratatui::layout::Direction::Horizontal)
+                .constraints([
+                    ratatui::layout::Constraint::Percentage(60),
+                    ratatui::layout::Constraint::Percentage(40),
+                ].as_ref())
+                .split(chunks[0]);
+            frame.render_widget(&self.ping_component, top[0]);
+            frame.render_widget(&self.udp_component, top[1]);
+        } else {
+            frame.render_widget(&self.ping_component, chunks[0]);
+        }
+        if self.show_details {
+            frame.render_widget(&self.timing_component, chunks[1]);
+        }
+        frame.render_widget(&self.download_component, chunks[2]);
+        frame.render_widget(&self.upload_component, chunks[3]);
+    }
+
     fn handle_crossterm_events(&mut self) -> Result<()> {
         // Usar poll para verificar si hay eventos disponibles sin bloquear
         if event::poll(Duration::from_millis(0))? {
@@ -110,14 +389,65 @@ impl App {
     }
 
     fn on_key_event(&mut self, key: KeyEvent) {
+        if self.show_picker {
+            self.on_picker_key_event(key);
+            return;
+        }
         match (key.modifiers, key.code) {
             (_, KeyCode::Esc | KeyCode::Char('q'))
             | (KeyModifiers::CONTROL, KeyCode::Char('c') | KeyCode::Char('C')) => self.quit(),
             
             // Medir latencia (no bloqueante)
-            (_, KeyCode::Enter) => {
+            (_, KeyCode::Enter) if self.ready() => {
                 self.test_service.run_full_test();
             }
+            (_, KeyCode::Tab) => {
+                self.tab = self.tab.next();
+                // The path is only probed while it is on screen
+                if self.tab == AppTab::Path {
+                    let host = IcmpTester::host_of(self.target.as_str());
+                    self.traceroute_component = TracerouteComponent::default();
+                    self.traceroute_component.set_target(host.clone());
+                    self.traceroute = Some(Traceroute::new(host.as_str()).start());
+                } else {
+                    self.traceroute = None;
+                }
+            }
+            (_, KeyCode::Char('r')) if self.ready() => {
+                self.test_service.run_responsiveness();
+            }
+            (_, KeyCode::Char('s')) if !self.test_service.get_testing() && self.selection.is_none() && !self.servers.get_servers().is_empty() => {
+                self.show_picker = true;
+            }
+            (_, KeyCode::Char('d')) => {
+                self.show_details = !self.show_details;
+            }
+            (_, KeyCode::Char('b')) if !self.test_service.get_testing() && self.uses_server_list() && self.server_error.is_none() => {
+                self.backend_kind = self.backend_kind.next();
+                self.test_service.set_tester(self.build_backend(&self.server));
+            }
+            _ => {}
+        }
+    }
+
+    /// Keys while the server picker is open; typed characters go to the
+    /// filter.
+    fn on_picker_key_event(&mut self, key: KeyEvent) {
+        match (key.modifiers, key.code) {
+            (KeyModifiers::CONTROL, KeyCode::Char('c') | KeyCode::Char('C')) => self.quit(),
+            (_, KeyCode::Esc) => self.show_picker = false,
+            (_, KeyCode::Enter) => {
+                if let Some(server) = self.picker_component.selected().cloned() {
+                    self.use_server(server);
+                }
+                self.show_picker = false;
+            }
+            (_, KeyCode::Up) => self.picker_component.move_selection(-1),
+            (_, KeyCode::Down) => self.picker_component.move_selection(1),
+            (_, KeyCode::PageUp) => self.picker_component.move_selection(-10),
+            (_, KeyCode::PageDown) => self.picker_component.move_selection(10),
+            (_, KeyCode::Backspace) => self.picker_component.pop_filter(),
+            (_, KeyCode::Char(c)) => self.picker_component.push_filter(c),
             _ => {}
         }
     }
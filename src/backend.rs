@@ -0,0 +1,75 @@
+use std::{fmt, future::Future, io::{Error, ErrorKind}};
+
+use clap::ValueEnum;
+use futures_util::future::BoxFuture;
+use tokio::sync::mpsc;
+
+use crate::{http_tester::{latency_series, latency_while, HttpDownloadMeasurement, HttpLatencyMeasurement, HttpThroughputSample, HttpTimingBreakdown, HttpUploadMeasurement}, responsiveness::ResponsivenessMeasurement};
+
+/// A provider that `HttpTestService` can run a full test against. Latency is
+/// a single probe in milliseconds; the service builds idle and loaded series
+/// out of it. Transfers report progress through `progress` while they run.
+pub trait SpeedTestBackend: Send + Sync {
+    /// Short name shown next to the server in the title bar.
+    fn name(&self) -> &str;
+
+    fn measure_latency(&self) -> BoxFuture<'_, Result<f64, Error>>;
+
+    /// Connection phase breakdowns, for backends that can collect them.
+    fn measure_timings(&self, _count: usize) -> BoxFuture<'_, Vec<HttpTimingBreakdown>> {
+        Box::pin(async { Vec::new() })
+    }
+
+    fn measure_download(&self, progress: mpsc::UnboundedSender<HttpThroughputSample>) -> BoxFuture<'_, Result<HttpDownloadMeasurement, Error>>;
+
+    fn measure_upload(&self, progress: mpsc::UnboundedSender<HttpThroughputSample>) -> BoxFuture<'_, Result<HttpUploadMeasurement, Error>>;
+
+    /// Responsiveness under a saturating download, for backends that speak
+    /// HTTP. Load progress is reported through `progress`.
+    fn measure_responsiveness(&self, _progress: mpsc::UnboundedSender<HttpThroughputSample>) -> BoxFuture<'_, Result<ResponsivenessMeasurement, Error>> {
+        Box::pin(async { Err(Error::new(ErrorKind::Unsupported, format!("not supported by the {} backend", self.name()))) })
+    }
+
+    /// Client address and ISP as the server sees them, for backends that
+    /// can ask.
+    fn client_info(&self) -> BoxFuture<'_, Option<String>> {
+        Box::pin(async { None })
+    }
+}
+
+impl dyn SpeedTestBackend {
+    pub async fn measure_latency_multiple(&self, count: usize) -> HttpLatencyMeasurement {
+        latency_series(count, || self.measure_latency()).await
+    }
+
+    pub async fn measure_latency_while<F: Future>(&self, task: F) -> (F::Output, HttpLatencyMeasurement) {
+        latency_while(task, || self.measure_latency()).await
+    }
+}
+
+/// Protocol used to talk to the selected server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum BackendKind {
+    /// HTTP endpoints under `/speedtest`
+    Http,
+    /// Line based TCP protocol on the server's `host` port
+    Tcp,
+}
+
+impl BackendKind {
+    pub fn next(&self) -> Self {
+        match self {
+            BackendKind::Http => BackendKind::Tcp,
+            BackendKind::Tcp => BackendKind::Http,
+        }
+    }
+}
+
+impl fmt::Display for BackendKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackendKind::Http => write!(f, "HTTP"),
+            BackendKind::Tcp => write!(f, "TCP"),
+        }
+    }
+}
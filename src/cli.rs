@@ -0,0 +1,125 @@
+use std::net::SocketAddr;
+
+use clap::{Args, Parser, Subcommand};
+
+use reqwest::header::{HeaderName, HeaderValue};
+
+use crate::{backend::BackendKind, custom_tester::{parse_header, UploadMethod}};
+
+#[derive(Debug, Clone, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Number of parallel connections used by the download and upload tests
+    #[arg(short, long, default_value_t = 4, value_parser = clap::value_parser!(u16).range(1..=64))]
+    pub connections: u16,
+
+    /// Duration in seconds of each download and upload phase
+    #[arg(short, long, default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..=120))]
+    pub duration: u64,
+
+    /// Seconds at the start of each phase left out of the reported speed
+    #[arg(short, long, default_value_t = 2)]
+    pub warmup: u64,
+
+    /// Percentage of the fastest and of the slowest intervals left out of the reported speed
+    #[arg(short, long, default_value_t = 10, value_parser = clap::value_parser!(u8).range(0..=40))]
+    pub trim: u8,
+
+    /// Protocol used to test against the server, can be switched with `b`
+    #[arg(short, long, value_enum, default_value_t = BackendKind::Http)]
+    pub backend: BackendKind,
+
+    /// Test against this server instead of one from the speedtest.net list,
+    /// such as another machine running `speedtest-tui serve`
+    #[arg(short, long, value_name = "HOST:PORT", conflicts_with_all = ["librespeed", "download_urls", "iperf"])]
+    pub server: Option<String>,
+
+    /// Latitude used to find the nearest speedtest.net servers instead of
+    /// the one speedtest.net guesses from your IP
+    #[arg(long, value_name = "DEGREES", requires = "lon", allow_negative_numbers = true, value_parser = parse_latitude)]
+    pub lat: Option<f64>,
+
+    /// Longitude used together with `--lat`
+    #[arg(long, value_name = "DEGREES", requires = "lat", allow_negative_numbers = true, value_parser = parse_longitude)]
+    pub lon: Option<f64>,
+
+    /// Number of the nearest speedtest.net servers probed to pick the one
+    /// with the lowest latency; 1 tests the nearest one right away
+    #[arg(long, value_name = "N", default_value_t = 5, value_parser = clap::value_parser!(u8).range(1..=20))]
+    pub candidates: u8,
+
+    /// UDP echo responder for the jitter and packet loss test, such as the
+    /// one `speedtest-tui serve` runs; the `--server` one by default
+    #[arg(short, long, value_name = "HOST:PORT")]
+    pub udp: Option<String>,
+
+    /// Test against a LibreSpeed backend instead of the speedtest.net servers,
+    /// given as the URL of the directory holding `garbage.php`
+    #[arg(short, long, value_name = "URL")]
+    pub librespeed: Option<String>,
+
+    /// Test against your own endpoints instead of a speedtest server, downloading
+    /// this URL; can be repeated to spread the downloads over several URLs
+    #[arg(long = "download-url", value_name = "URL", conflicts_with = "librespeed")]
+    pub download_urls: Vec<String>,
+
+    /// URL that random data is uploaded to when testing against your own endpoints
+    #[arg(long, value_name = "URL", requires = "download_urls")]
+    pub upload_url: Option<String>,
+
+    /// HTTP method of the uploads to `--upload-url`
+    #[arg(long, value_enum, default_value_t = UploadMethod::Post, requires = "upload_url")]
+    pub upload_method: UploadMethod,
+
+    /// URL probed for latency when testing against your own endpoints,
+    /// the first download URL by default
+    #[arg(long, value_name = "URL", requires = "download_urls")]
+    pub latency_url: Option<String>,
+
+    /// Extra `Name: value` header sent with every request to your own
+    /// endpoints, such as an auth token; can be repeated
+    #[arg(short = 'H', long = "header", value_name = "HEADER", value_parser = parse_header, requires = "download_urls")]
+    pub headers: Vec<(HeaderName, HeaderValue)>,
+
+    /// Test against an `iperf3 -s` server instead of a speedtest server,
+    /// on port 5201 unless another one is given
+    #[arg(short, long, value_name = "HOST[:PORT]", conflicts_with_all = ["librespeed", "download_urls"])]
+    pub iperf: Option<String>,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum Command {
+    /// Serve the endpoints the HTTP and TCP backends use, so other machines
+    /// can test against this one
+    Serve(ServeArgs),
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct ServeArgs {
+    /// Address to listen on; HTTP and the speedtest.net TCP protocol share the port
+    #[arg(short, long, default_value = "0.0.0.0:8080")]
+    pub bind: SocketAddr,
+
+    /// Additional port that only speaks the speedtest.net TCP protocol
+    #[arg(short, long, value_name = "PORT")]
+    pub tcp_port: Option<u16>,
+}
+
+fn parse_degrees(value: &str, limit: f64) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(degrees) if degrees.abs() <= limit => Ok(degrees),
+        Ok(_) => Err(format!("must be between -{} and {}", limit, limit)),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn parse_latitude(value: &str) -> Result<f64, String> {
+    parse_degrees(value, 90.0)
+}
+
+fn parse_longitude(value: &str) -> Result<f64, String> {
+    parse_degrees(value, 180.0)
+}
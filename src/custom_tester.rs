@@ -0,0 +1,168 @@
+use clap::ValueEnum;
+use futures_util::future::BoxFuture;
+use reqwest::{header::{HeaderMap, HeaderName, HeaderValue}, Method};
+use std::{io::Error, sync::{atomic::{AtomicUsize, Ordering}, Arc}, time::Duration};
+use tokio::sync::mpsc;
+
+use crate::{backend::SpeedTestBackend, http_tester::{build_client, build_transfer_client, time_request, download_phase, upload_phase, HttpDownloadMeasurement, HttpTester, HttpThroughputSample, HttpTimingBreakdown, HttpUploadMeasurement, TransferSettings}, responsiveness::{responsiveness_phase, ResponsivenessMeasurement}};
+
+/// HTTP method used for the upload requests of `CustomTester`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum UploadMethod {
+    Post,
+    Put,
+    Patch,
+}
+
+impl UploadMethod {
+    pub fn to_method(self) -> Method {
+        match self {
+            UploadMethod::Post => Method::POST,
+            UploadMethod::Put => Method::PUT,
+            UploadMethod::Patch => Method::PATCH,
+        }
+    }
+}
+
+/// Parses a `Name: value` request header given on the command line.
+pub fn parse_header(header: &str) -> Result<(HeaderName, HeaderValue), String> {
+    let (name, value) = match header.split_once(':') {
+        Some(parts) => parts,
+        None => return Err(format!("expected `Name: value`, got `{}`", header)),
+    };
+    let name = match HeaderName::from_bytes(name.trim().as_bytes()) {
+        Ok(name) => name,
+        Err(e) => return Err(format!("invalid header name: {}", e)),
+    };
+    let value = match HeaderValue::from_str(value.trim()) {
+        Ok(value) => value,
+        Err(e) => return Err(format!("invalid header value: {}", e)),
+    };
+    Ok((name, value))
+}
+
+/// Measures against arbitrary endpoints, such as a CDN or an object store,
+/// instead of a speedtest server. Downloads cycle through `download_urls`
+/// as they are, uploads send random data to `upload_url` and latency is a
+/// HEAD request to `latency_url`. `headers` go with every request.
+#[derive(Debug, Clone)]
+pub struct CustomTester {
+    pub download_urls: Vec<String>,
+    pub upload_url: Option<String>,
+    pub upload_method: UploadMethod,
+    pub latency_url: String,
+    pub headers: HeaderMap,
+    pub settings: TransferSettings,
+}
+
+impl CustomTester {
+    /// Creates a tester that downloads from `download_urls` and uses the
+    /// first of them for latency until `set_latency_url` says otherwise.
+    pub fn new(download_urls: Vec<String>, settings: TransferSettings) -> Self {
+        CustomTester {
+            latency_url: download_urls.first().cloned().unwrap_or_default(),
+            download_urls,
+            upload_url: None,
+            upload_method: UploadMethod::Post,
+            headers: HeaderMap::new(),
+            settings,
+        }
+    }
+
+    pub fn set_upload(&mut self, url: &str, method: UploadMethod) {
+        self.upload_url = Some(url.to_string());
+        self.upload_method = method;
+    }
+
+    pub fn set_latency_url(&mut self, url: &str) {
+        self.latency_url = url.to_string();
+    }
+
+    pub fn set_headers(&mut self, headers: Vec<(HeaderName, HeaderValue)>) {
+        self.headers = headers.into_iter().collect();
+    }
+
+    pub async fn measure_latency(&self) -> Result<f64, Error> {
+        time_request(build_client(Duration::from_secs(10)).head(self.latency_url.as_str()).headers(self.headers.clone())).await
+    }
+
+    /// Downloads the URLs in turn, each stream taking the next one when its
+    /// previous request ends. The files are fetched whole, so there is no
+    /// size to adapt.
+    pub async fn measure_download(&self, progress: mpsc::UnboundedSender<HttpThroughputSample>) -> Result<HttpDownloadMeasurement, Error> {
+        if self.download_urls.is_empty() {
+            return Err(Error::other("No download URL given"));
+        }
+        let client = build_transfer_client(&self.settings);
+        let urls = self.download_urls.clone();
+        let headers = self.headers.clone();
+        let next = Arc::new(AtomicUsize::new(0));
+        download_phase(&client, &self.settings, vec![0], progress, move |client, _size| {
+            let url = &urls[next.fetch_add(1, Ordering::Relaxed) % urls.len()];
+            client.get(url.as_str()).headers(headers.clone())
+        }).await
+    }
+
+    /// Loads the link with the download URLs in turn and probes it with
+    /// HEAD requests to `latency_url` on new connections and to the first
+    /// download URL on the load ones.
+    pub async fn measure_responsiveness(&self, progress: mpsc::UnboundedSender<HttpThroughputSample>) -> Result<ResponsivenessMeasurement, Error> {
+        if self.download_urls.is_empty() {
+            return Err(Error::other("No download URL given"));
+        }
+        let urls = self.download_urls.clone();
+        let headers = self.headers.clone();
+        let next = Arc::new(AtomicUsize::new(0));
+        let probe = HttpTester::new(self.latency_url.as_str());
+        responsiveness_phase(&self.settings, probe, progress, move |client| {
+            let url = &urls[next.fetch_add(1, Ordering::Relaxed) % urls.len()];
+            client.get(url.as_str()).headers(headers.clone())
+        }, |client| {
+            client.head(self.download_urls[0].as_str()).headers(self.headers.clone())
+        }).await
+    }
+
+    pub async fn measure_upload(&self, progress: mpsc::UnboundedSender<HttpThroughputSample>) -> Result<HttpUploadMeasurement, Error> {
+        let url = match &self.upload_url {
+            Some(url) => url.clone(),
+            None => return Err(Error::other("No upload URL given")),
+        };
+        let client = build_transfer_client(&self.settings);
+        let method = self.upload_method.to_method();
+        let headers = self.headers.clone();
+        upload_phase(&client, &self.settings, progress, move |client| {
+            client.request(method.clone(), url.as_str()).headers(headers.clone())
+        }).await
+    }
+}
+
+impl SpeedTestBackend for CustomTester {
+    fn name(&self) -> &str {
+        "Custom"
+    }
+
+    fn measure_latency(&self) -> BoxFuture<'_, Result<f64, Error>> {
+        Box::pin(CustomTester::measure_latency(self))
+    }
+
+    /// Timing probes go to `latency_url` without the extra headers; an
+    /// error status still times the connection.
+    fn measure_timings(&self, count: usize) -> BoxFuture<'_, Vec<HttpTimingBreakdown>> {
+        Box::pin(async move {
+            let probe = HttpTester::new(self.latency_url.as_str());
+            probe.measure_timings(count).await
+        })
+    }
+
+    fn measure_download(&self, progress: mpsc::UnboundedSender<HttpThroughputSample>) -> BoxFuture<'_, Result<HttpDownloadMeasurement, Error>> {
+        Box::pin(CustomTester::measure_download(self, progress))
+    }
+
+    fn measure_upload(&self, progress: mpsc::UnboundedSender<HttpThroughputSample>) -> BoxFuture<'_, Result<HttpUploadMeasurement, Error>> {
+        Box::pin(CustomTester::measure_upload(self, progress))
+    }
+
+    fn measure_responsiveness(&self, progress: mpsc::UnboundedSender<HttpThroughputSample>) -> BoxFuture<'_, Result<ResponsivenessMeasurement, Error>> {
+        Box::pin(CustomTester::measure_responsiveness(self, progress))
+    }
+}
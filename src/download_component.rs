@@ -1,30 +1,63 @@
+use std::time::Duration;
+
 use ratatui::{style::{Color, Style, Stylize}, text::{Line, Text}, widgets::{Block, Widget}};
 
-use crate::http_tester::HttpDownloadMeasurement;
+use crate::http_tester::{HttpDownloadMeasurement, HttpThroughputSample};
 
-#[derive(Default, Clone)]
+#[derive(Clone)]
 pub struct DownloadComponent {
-    download_measurement: HttpDownloadMeasurement,
+    download_measurement: Result<HttpDownloadMeasurement, String>,
+    progress: Option<HttpThroughputSample>,
     active: bool,
 }
 
+impl Default for DownloadComponent {
+    fn default() -> Self {
+        DownloadComponent {
+            download_measurement: Ok(HttpDownloadMeasurement::default()),
+            progress: None,
+            active: false,
+        }
+    }
+}
+
 impl DownloadComponent {
-    pub fn set_download_measurement(&mut self, measurement: HttpDownloadMeasurement) {
+    pub fn set_download_measurement(&mut self, measurement: Result<HttpDownloadMeasurement, String>) {
         self.download_measurement = measurement;
     }
+    pub fn set_progress(&mut self, progress: Option<HttpThroughputSample>) {
+        self.progress = progress;
+    }
     pub fn set_active(&mut self, active: bool) {
         self.active = active;
     }
+
+    fn lines(bits: u64, duration: Duration, speed: f64, raw_speed: f64) -> Text<'static> {
+        Text::from(vec![
+            Line::from(format!("Downloaded data: {} MB", bits / (1024 * 1024 * 8)).green()),
+            Line::from(format!("Duration: {:.2} seconds", duration.as_secs_f64()).red()),
+            Line::from(format!("Speed: {:.2} Mbps (raw {:.2} Mbps)", speed / (1024 * 1024) as f64, raw_speed / (1024 * 1024) as f64).blue()),
+        ])
+    }
 }
 
 impl Widget for &DownloadComponent {
     fn render(self, area: ratatui::prelude::Rect, buf: &mut ratatui::prelude::Buffer) {
         let title = Line::from("Download Speed").bold();
-        let content = Text::from(vec![
-            Line::from(format!("Downloaded data: {} MB", self.download_measurement.bits / (1024 * 1024 * 8)).green()),
-            Line::from(format!("Duration: {:.2} seconds", self.download_measurement.duration.as_secs_f64()).red()),
-            Line::from(format!("Speed: {:.2} Mbps", self.download_measurement.speed / (1024 * 1024) as f64).blue()),
-        ]);
+        // While the phase runs show the live sample instead of the last result
+        let content = match (&self.progress, self.active, &self.download_measurement) {
+            (Some(progress), true, _) => DownloadComponent::lines(progress.bits, progress.elapsed, progress.speed, progress.speed),
+            (_, _, Ok(measurement)) => {
+                let mut text = DownloadComponent::lines(measurement.bits, measurement.duration, measurement.speed, measurement.raw_speed);
+                // Failed requests were retried, but the streams lost time on them
+                if measurement.failed_requests > 0 {
+                    text.push_line(Line::from(format!("{} failed requests retried", measurement.failed_requests)).yellow());
+                }
+                text
+            }
+            // A failed phase shows why instead of a speed of zero
+            (_, _, Err(e)) => Text::from(Line::from(format!("Download failed: {}", e)).bold().red()),
+        };
 
         let block = Block::bordered()
             .title(title)
@@ -1,5 +1,51 @@
-use reqwest::{Client};
-use std::{io::Error, time::{Duration, Instant}};
+use bytes::Bytes;
+use futures_util::{future::BoxFuture, stream, StreamExt};
+use rand::RngCore;
+use reqwest::{header::CONTENT_LENGTH, Body, Client, RequestBuilder};
+use std::{fmt, future::Future, io::Error, sync::{atomic::{AtomicU64, AtomicUsize, Ordering}, Arc}, time::{Duration, Instant}};
+use tokio::{io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt}, net::{lookup_host, TcpStream}, sync::{mpsc, oneshot}, task::{JoinHandle, JoinSet}, time::{timeout, timeout_at}};
+use tokio_native_tls::{native_tls, TlsConnector};
+
+use crate::{backend::SpeedTestBackend, responsiveness::{responsiveness_phase, ResponsivenessMeasurement}};
+
+const USER_AGENT: &str = "Mozilla/5.0 (compatible; speedtest-tui/1.0)";
+const DEFAULT_CONNECTIONS: usize = 4;
+const DEFAULT_TEST_DURATION: Duration = Duration::from_secs(10);
+/// Start of a transfer phase left out of the headline speed, covering
+/// connection setup and TCP slow start.
+const DEFAULT_WARMUP: Duration = Duration::from_secs(2);
+/// Fraction of the fastest and of the slowest intervals dropped from the
+/// headline speed.
+const DEFAULT_TRIM_FRACTION: f64 = 0.1;
+/// Upper bound for a single timing breakdown probe.
+const TIMING_PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Pause between latency probes sent while a transfer phase is running.
+const LOADED_PROBE_INTERVAL: Duration = Duration::from_millis(250);
+/// Extra time given to the reqwest timeout of transfer requests; the phase
+/// deadline is what normally ends them.
+const TRANSFER_TIMEOUT_GRACE: Duration = Duration::from_secs(5);
+/// Requests shorter than this are dominated by request overhead, so the
+/// adaptive ladder keeps stepping up until a request takes at least this long.
+const MIN_REQUEST_DURATION: Duration = Duration::from_millis(1000);
+/// Pause before a stream sends its next request after one failed.
+const RETRY_DELAY: Duration = Duration::from_millis(100);
+/// How often a running transfer phase reports a progress sample.
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(200);
+/// Upload bodies are streamed in pieces of this size so sent bytes can be
+/// counted while the request is still in flight.
+const UPLOAD_PIECE_SIZE: usize = 64 * 1024;
+/// Transfer chunk sizes in bytes, smallest first, used by uploads and by
+/// protocols that accept an arbitrary size. The random payload is generated
+/// once with the largest size and every chunk is a slice of it.
+pub const CHUNK_SIZES: [usize; 6] = [
+    256 * 1024,
+    512 * 1024,
+    1024 * 1024,
+    2 * 1024 * 1024,
+    4 * 1024 * 1024,
+    8 * 1024 * 1024,
+];
+
 pub enum HttpDownloadSize {
     S250,
     S350,
@@ -53,155 +99,901 @@ impl HttpDownloadSize {
     }
 }
 
+/// Walks a ladder of transfer sizes, starting at the smallest and stepping up
+/// while requests finish faster than `MIN_REQUEST_DURATION`. A request that
+/// takes several times longer than that steps back down.
+#[derive(Debug, Clone)]
+pub struct AdaptiveLadder {
+    sizes: Vec<usize>,
+    index: usize,
+}
+
+impl AdaptiveLadder {
+    pub fn new(sizes: Vec<usize>) -> Self {
+        AdaptiveLadder { sizes, index: 0 }
+    }
+
+    pub fn current(&self) -> usize {
+        self.sizes[self.index]
+    }
+
+    pub fn record(&mut self, elapsed: Duration) {
+        if elapsed < MIN_REQUEST_DURATION {
+            if self.index + 1 < self.sizes.len() {
+                self.index += 1;
+            }
+        } else if elapsed > MIN_REQUEST_DURATION * 4 && self.index > 0 {
+            self.index -= 1;
+        }
+    }
+}
+
+/// Byte counter shared by every stream of a transfer phase.
+#[derive(Debug, Default, Clone)]
+pub struct ByteCounter(Arc<AtomicU64>);
+
+impl ByteCounter {
+    pub fn add(&self, bytes: u64) {
+        self.0.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Samples a `ByteCounter` every `SAMPLE_INTERVAL` and sends the running
+/// total and instantaneous speed to `progress` until the phase finishes.
+pub struct ThroughputMeter {
+    counter: ByteCounter,
+    stop: oneshot::Sender<()>,
+    sampler: JoinHandle<Vec<HttpThroughputSample>>,
+}
+
+impl ThroughputMeter {
+    pub fn start(progress: mpsc::UnboundedSender<HttpThroughputSample>) -> Self {
+        let counter = ByteCounter::default();
+        let (stop, mut stop_rx) = oneshot::channel();
+        let sampled = counter.clone();
+        let sampler = tokio::spawn(async move {
+            let start = Instant::now();
+            let mut ticker = tokio::time::interval(SAMPLE_INTERVAL);
+            ticker.tick().await; // The first tick completes immediately
+            let mut last_bytes = 0;
+            let mut last_elapsed = Duration::ZERO;
+            let mut samples = Vec::new();
+            loop {
+                tokio::select! {
+                    _ = ticker.tick() => {}
+                    _ = &mut stop_rx => break,
+                }
+                let bytes = sampled.get();
+                let elapsed = start.elapsed();
+                let interval = (elapsed - last_elapsed).as_secs_f64();
+                let speed = ((bytes - last_bytes) * 8) as f64 / interval;
+                let sample = HttpThroughputSample { bits: bytes * 8, elapsed, speed };
+                let _ = progress.send(sample.clone());
+                samples.push(sample);
+                last_bytes = bytes;
+                last_elapsed = elapsed;
+            }
+            samples
+        });
+        ThroughputMeter { counter, stop, sampler }
+    }
+
+    pub fn counter(&self) -> ByteCounter {
+        self.counter.clone()
+    }
+
+    /// Stops the sampler and returns the total bytes counted together with
+    /// the per-interval time series.
+    pub async fn finish(self) -> (u64, Vec<HttpThroughputSample>) {
+        let _ = self.stop.send(());
+        let samples = self.sampler.await.unwrap_or_default();
+        (self.counter.get(), samples)
+    }
+}
+
+/// Mean of the per-interval speeds once `warmup` has passed, ignoring the
+/// fastest and the slowest `trim_fraction` of the intervals. Returns `None`
+/// when no interval falls after the warm-up window.
+pub fn trimmed_speed(samples: &[HttpThroughputSample], warmup: Duration, trim_fraction: f64) -> Option<f64> {
+    let mut speeds: Vec<f64> = samples.iter()
+        .filter(|sample| sample.elapsed > warmup)
+        .map(|sample| sample.speed)
+        .collect();
+    if speeds.is_empty() {
+        return None;
+    }
+    speeds.sort_by(|a, b| a.total_cmp(b));
+    let trim = (speeds.len() as f64 * trim_fraction.clamp(0.0, 0.49)) as usize;
+    let kept = &speeds[trim..speeds.len() - trim];
+    Some(kept.iter().sum::<f64>() / kept.len() as f64)
+}
+
+/// Counts a failed request of a transfer stream and waits `RETRY_DELAY`,
+/// or until `deadline`, before the stream sends its next one. One failed
+/// request, such as a transient 5xx, doesn't end the stream.
+async fn request_failed(failures: &AtomicUsize, deadline: Instant) {
+    failures.fetch_add(1, Ordering::Relaxed);
+    tokio::time::sleep_until(deadline.min(Instant::now() + RETRY_DELAY).into()).await;
+}
+
+/// Waits for every stream of a transfer phase, keeping the last error so a
+/// phase where nothing got through can still report why.
+pub async fn join_streams(mut streams: JoinSet<Result<(), Error>>) -> Option<Error> {
+    let mut last_error = None;
+    while let Some(result) = streams.join_next().await {
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => last_error = Some(e),
+            Err(e) => last_error = Some(Error::other(format!("Stream task failed: {}", e))),
+        }
+    }
+    last_error
+}
+
+pub fn random_payload(size: usize) -> Bytes {
+    let mut data = vec![0u8; size];
+    rand::rng().fill_bytes(&mut data);
+    Bytes::from(data)
+}
+
+/// Sends `count` probes, counting failed ones as lost instead of giving up
+/// on the whole series.
+pub async fn latency_series<F, Fut>(count: usize, mut probe: F) -> HttpLatencyMeasurement
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<f64, Error>>,
+{
+    let mut samples = Vec::with_capacity(count);
+    let mut lost = 0;
+
+    for _ in 0..count {
+        match probe().await {
+            Ok(latency) => {
+                samples.push(latency);
+            }
+            Err(_) => {
+                lost += 1;
+            },
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(300)).await; // Sleep to avoid overwhelming the server
+    }
+
+    HttpLatencyMeasurement::from_samples(samples, lost)
+}
+
+/// Keeps sending probes until `task` completes, returning its output
+/// together with the latency measured under that load.
+pub async fn latency_while<T, F, Fut>(task: T, mut probe: F) -> (T::Output, HttpLatencyMeasurement)
+where
+    T: Future,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<f64, Error>>,
+{
+    let mut samples = Vec::new();
+    let mut lost = 0;
+    tokio::pin!(task);
+
+    loop {
+        let probe = probe();
+        let probe = async {
+            let result = probe.await;
+            tokio::time::sleep(LOADED_PROBE_INTERVAL).await;
+            result
+        };
+        tokio::select! {
+            output = &mut task => return (output, HttpLatencyMeasurement::from_samples(samples, lost)),
+            result = probe => match result {
+                Ok(latency) => samples.push(latency),
+                Err(_) => lost += 1,
+            },
+        }
+    }
+}
+
+/// Progress of a running transfer phase.
+#[derive(Debug, Default, Clone)]
+pub struct HttpThroughputSample {
+    pub bits: u64, // transferred so far
+    pub elapsed: Duration,
+    pub speed: f64, // bits per second over the last sample interval
+}
+
 #[derive(Debug, Default, Clone)]
 pub struct HttpLatencyMeasurement {
     pub min: f64,
     pub max: f64,
     pub avg: f64,
-    pub total_measurments: u8,
+    pub median: f64,
+    pub p90: f64,
+    pub p99: f64,
+    pub std_dev: f64,
+    pub jitter: f64, // mean absolute difference between consecutive samples
+    pub samples: Vec<f64>, // milliseconds, in the order they were measured
+    pub total_measurments: usize, // probes that got a response
+    pub lost: usize, // probes that failed or timed out
+    pub timings: Vec<HttpTimingBreakdown>,
+}
+
+impl HttpLatencyMeasurement {
+    pub fn from_samples(samples: Vec<f64>, lost: usize) -> Self {
+        if samples.is_empty() {
+            return HttpLatencyMeasurement { lost, ..Default::default() };
+        }
+        let count = samples.len() as f64;
+        let avg = samples.iter().sum::<f64>() / count;
+        let variance = samples.iter().map(|sample| (sample - avg).powi(2)).sum::<f64>() / count;
+        let jitter = if samples.len() > 1 {
+            samples.windows(2).map(|pair| (pair[1] - pair[0]).abs()).sum::<f64>() / (count - 1.0)
+        } else {
+            0.0
+        };
+
+        let mut sorted = samples.clone();
+        sorted.sort_by(|a, b| a.total_cmp(b));
+        HttpLatencyMeasurement {
+            min: sorted[0],
+            max: sorted[sorted.len() - 1],
+            avg,
+            median: Self::percentile(&sorted, 50.0),
+            p90: Self::percentile(&sorted, 90.0),
+            p99: Self::percentile(&sorted, 99.0),
+            std_dev: variance.sqrt(),
+            jitter,
+            total_measurments: samples.len(),
+            samples,
+            lost,
+            timings: Vec::new(),
+        }
+    }
+
+    pub fn sent(&self) -> usize {
+        self.total_measurments + self.lost
+    }
+
+    pub fn loss_percent(&self) -> f64 {
+        if self.sent() == 0 {
+            return 0.0;
+        }
+        self.lost as f64 * 100.0 / self.sent() as f64
+    }
+
+    /// Nearest-rank percentile of an ascending slice.
+    fn percentile(sorted: &[f64], percentile: f64) -> f64 {
+        let rank = (percentile / 100.0 * sorted.len() as f64).ceil() as usize;
+        sorted[rank.clamp(1, sorted.len()) - 1]
+    }
 }
 
+/// Time spent in each phase of one request on a fresh connection.
 #[derive(Debug, Default, Clone)]
-pub struct HttpUploadMeasurement {
-    pub bits: u64,
-    pub duration: Duration,
-    pub speed: f64, // bits per second
+pub struct HttpTimingBreakdown {
+    pub dns: Duration,
+    pub connect: Duration,
+    pub tls: Option<Duration>, // None for plain HTTP
+    pub ttfb: Duration, // request written until the first response byte
+    pub total: Duration,
+}
+
+impl HttpTimingBreakdown {
+    /// Field-wise mean of `timings`, `None` when there are none.
+    pub fn average(timings: &[HttpTimingBreakdown]) -> Option<HttpTimingBreakdown> {
+        if timings.is_empty() {
+            return None;
+        }
+        let count = timings.len() as u32;
+        let tls: Vec<Duration> = timings.iter().filter_map(|timing| timing.tls).collect();
+        Some(HttpTimingBreakdown {
+            dns: timings.iter().map(|timing| timing.dns).sum::<Duration>() / count,
+            connect: timings.iter().map(|timing| timing.connect).sum::<Duration>() / count,
+            tls: if tls.is_empty() { None } else { Some(tls.iter().sum::<Duration>() / tls.len() as u32) },
+            ttfb: timings.iter().map(|timing| timing.ttfb).sum::<Duration>() / count,
+            total: timings.iter().map(|timing| timing.total).sum::<Duration>() / count,
+        })
+    }
+}
+
+/// Grade for how much latency grows while the link is loaded, using the
+/// thresholds popularised by the Waveform bufferbloat test.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BufferbloatGrade {
+    APlus,
+    A,
+    B,
+    C,
+    D,
+    F,
 }
 
+impl BufferbloatGrade {
+    pub fn from_increase(increase_ms: f64) -> Self {
+        match increase_ms {
+            x if x < 5.0 => BufferbloatGrade::APlus,
+            x if x < 30.0 => BufferbloatGrade::A,
+            x if x < 60.0 => BufferbloatGrade::B,
+            x if x < 200.0 => BufferbloatGrade::C,
+            x if x < 400.0 => BufferbloatGrade::D,
+            _ => BufferbloatGrade::F,
+        }
+    }
+
+    /// Grades the worst average latency increase of `loaded` over `idle`,
+    /// returning it along with the increase in milliseconds. Measurements
+    /// without any response are ignored.
+    pub fn from_latencies(idle: &HttpLatencyMeasurement, loaded: &[&HttpLatencyMeasurement]) -> Option<(Self, f64)> {
+        if idle.total_measurments == 0 {
+            return None;
+        }
+        let increase = loaded.iter()
+            .filter(|latency| latency.total_measurments > 0)
+            .map(|latency| (latency.avg - idle.avg).max(0.0))
+            .reduce(f64::max)?;
+        Some((Self::from_increase(increase), increase))
+    }
+}
+
+impl fmt::Display for BufferbloatGrade {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let grade = match self {
+            BufferbloatGrade::APlus => "A+",
+            BufferbloatGrade::A => "A",
+            BufferbloatGrade::B => "B",
+            BufferbloatGrade::C => "C",
+            BufferbloatGrade::D => "D",
+            BufferbloatGrade::F => "F",
+        };
+        write!(f, "{}", grade)
+    }
+}
+
+/// Result of a download or upload phase.
 #[derive(Debug, Default, Clone)]
-pub struct HttpDownloadMeasurement {
+pub struct TransferMeasurement {
     pub bits: u64,
     pub duration: Duration,
-    pub speed: f64, // bits per second
+    pub speed: f64, // bits per second, without warm-up and outlier intervals
+    pub raw_speed: f64, // bits per second over the whole phase
+    pub samples: Vec<HttpThroughputSample>,
+    pub loaded_latency: HttpLatencyMeasurement, // probed while the phase ran
+    pub failed_requests: usize, // requests that failed and were retried
 }
 
-#[derive(Debug, Default, Clone)]
+impl TransferMeasurement {
+    /// Builds the measurement of a finished transfer phase, failing with
+    /// `error` (or a generic one) when nothing was moved.
+    pub fn from_transfer(bytes: u64, duration: Duration, samples: Vec<HttpThroughputSample>, warmup: Duration, trim_fraction: f64, error: Option<Error>) -> Result<Self, Error> {
+        if bytes == 0 {
+            return Err(error.unwrap_or_else(|| Error::other("No data transferred")));
+        }
+        let bits = bytes * 8; // Convert bytes to bits
+        let raw_speed = bits as f64 / duration.as_secs_f64(); // bits per second
+        let speed = trimmed_speed(&samples, warmup, trim_fraction).unwrap_or(raw_speed);
+        Ok(TransferMeasurement { bits, duration, speed, raw_speed, samples, ..Default::default() })
+    }
+}
+
+pub type HttpDownloadMeasurement = TransferMeasurement;
+pub type HttpUploadMeasurement = TransferMeasurement;
+
+/// How long and over how many connections a transfer phase runs, and how
+/// its headline speed is computed. Shared by every backend.
+#[derive(Debug, Clone)]
+pub struct TransferSettings {
+    pub connections: usize,
+    pub test_duration: Duration,
+    pub warmup: Duration,
+    pub trim_fraction: f64,
+}
+
+impl Default for TransferSettings {
+    fn default() -> Self {
+        TransferSettings {
+            connections: DEFAULT_CONNECTIONS,
+            test_duration: DEFAULT_TEST_DURATION,
+            warmup: DEFAULT_WARMUP,
+            trim_fraction: DEFAULT_TRIM_FRACTION,
+        }
+    }
+}
+
+pub fn build_client(timeout: Duration) -> Client {
+    Client::builder()
+        .timeout(timeout)
+        .user_agent(USER_AGENT)
+        .build()
+        .expect("Failed to build Client")
+}
+
+/// Sends `request` and returns how long the response took to arrive, in
+/// milliseconds. Only a success status counts as an answer.
+pub async fn time_request(request: RequestBuilder) -> Result<f64, Error> {
+    let start = Instant::now();
+    let response = request.send().await;
+    match response {
+        Ok(resp) => {
+            if resp.status().is_success() {
+                let duration = start.elapsed();
+                Ok(duration.as_secs_f64() * 1000.0)
+            } else {
+                Err(Error::other(format!("Request failed: {}", resp.status())))
+            }
+        }
+        Err(e) => {
+            Err(Error::other(format!("Request error: {}", e)))
+        }
+    }
+}
+
+/// Client for transfer requests; the phase deadline is what normally ends
+/// them, the timeout only catches requests that hang past it.
+pub fn build_transfer_client(settings: &TransferSettings) -> Client {
+    build_client(settings.test_duration + TRANSFER_TIMEOUT_GRACE)
+}
+
+/// Downloads over `settings.connections` parallel streams until
+/// `settings.test_duration` runs out. Each stream adapts its size along
+/// `sizes` and asks `request` for the request of that size; the bytes
+/// received by all of them are aggregated into one measurement. Progress is
+/// sent to `progress` while the phase runs.
+pub async fn download_phase<F>(client: &Client, settings: &TransferSettings, sizes: Vec<usize>, progress: mpsc::UnboundedSender<HttpThroughputSample>, request: F) -> Result<HttpDownloadMeasurement, Error>
+where
+    F: Fn(&Client, usize) -> RequestBuilder + Clone + Send + 'static,
+{
+    let meter = ThroughputMeter::start(progress);
+    let start = Instant::now();
+    let deadline = start + settings.test_duration;
+    let failures = Arc::new(AtomicUsize::new(0));
+    let mut streams = JoinSet::new();
+    for _ in 0..settings.connections.max(1) {
+        let client = client.clone();
+        let request = request.clone();
+        let counter = meter.counter();
+        let failures = failures.clone();
+        let mut ladder = AdaptiveLadder::new(sizes.clone());
+        streams.spawn(async move {
+            let mut last_error = None;
+            while Instant::now() < deadline {
+                let size = ladder.current();
+                let request_start = Instant::now();
+                match download_body(request(&client, size), deadline, &counter).await {
+                    Ok(()) => ladder.record(request_start.elapsed()),
+                    Err(e) => {
+                        last_error = Some(e);
+                        request_failed(&failures, deadline).await;
+                    }
+                }
+            }
+            last_error.map_or(Ok(()), Err)
+        });
+    }
+
+    let error = join_streams(streams).await;
+    let (bytes, samples) = meter.finish().await;
+    let duration = start.elapsed().min(settings.test_duration);
+    let mut download = HttpDownloadMeasurement::from_transfer(bytes, duration, samples, settings.warmup, settings.trim_fraction, error)?;
+    download.failed_requests = failures.load(Ordering::Relaxed);
+    Ok(download)
+}
+
+/// Streams one response body until it ends or the deadline passes, adding
+/// every received chunk to `counter`.
+pub async fn download_body(request: RequestBuilder, deadline: Instant, counter: &ByteCounter) -> Result<(), Error> {
+    let resp = match timeout_at(deadline.into(), request.send()).await {
+        Ok(Ok(resp)) => resp,
+        Ok(Err(e)) => return Err(Error::other(format!("Request error: {}", e))),
+        Err(_) => return Ok(()),
+    };
+    if !resp.status().is_success() {
+        return Err(Error::other(format!("Request failed: {}", resp.status())));
+    }
+
+    let mut body = resp.bytes_stream();
+    loop {
+        match timeout_at(deadline.into(), body.next()).await {
+            Ok(Some(Ok(chunk))) => counter.add(chunk.len() as u64),
+            Ok(None) | Err(_) => break,
+            Ok(Some(Err(e))) => return Err(Error::other(format!("Request error: {}", e))),
+        }
+    }
+    Ok(())
+}
+
+/// Uploads over `settings.connections` parallel streams until
+/// `settings.test_duration` runs out, each stream adapting its chunk size
+/// along `CHUNK_SIZES` and sending it with the request built by `request`.
+/// The body is random so compressing proxies can't inflate the result.
+/// Progress is sent to `progress` while the phase runs.
+pub async fn upload_phase<F>(client: &Client, settings: &TransferSettings, progress: mpsc::UnboundedSender<HttpThroughputSample>, request: F) -> Result<HttpUploadMeasurement, Error>
+where
+    F: Fn(&Client) -> RequestBuilder + Clone + Send + 'static,
+{
+    let payload = random_payload(CHUNK_SIZES[CHUNK_SIZES.len() - 1]);
+
+    let meter = ThroughputMeter::start(progress);
+    let start = Instant::now();
+    let deadline = start + settings.test_duration;
+    let failures = Arc::new(AtomicUsize::new(0));
+    let mut streams = JoinSet::new();
+    for _ in 0..settings.connections.max(1) {
+        let client = client.clone();
+        let request = request.clone();
+        let payload = payload.clone();
+        let counter = meter.counter();
+        let failures = failures.clone();
+        let mut ladder = AdaptiveLadder::new(CHUNK_SIZES.to_vec());
+        streams.spawn(async move {
+            let mut last_error = None;
+            while Instant::now() < deadline {
+                let size = ladder.current();
+                let request_start = Instant::now();
+                // A chunk still in flight at the deadline is dropped; the pieces it already sent stay counted
+                match timeout_at(deadline.into(), upload_body(request(&client), payload.slice(..size), &counter)).await {
+                    Ok(Ok(())) => ladder.record(request_start.elapsed()),
+                    Ok(Err(e)) => {
+                        last_error = Some(e);
+                        request_failed(&failures, deadline).await;
+                    }
+                    Err(_) => break,
+                }
+            }
+            last_error.map_or(Ok(()), Err)
+        });
+    }
+
+    let error = join_streams(streams).await;
+    let (bytes, samples) = meter.finish().await;
+    let duration = start.elapsed().min(settings.test_duration);
+    let mut upload = HttpUploadMeasurement::from_transfer(bytes, duration, samples, settings.warmup, settings.trim_fraction, error)?;
+    upload.failed_requests = failures.load(Ordering::Relaxed);
+    Ok(upload)
+}
+
+/// Splits `chunk` into `UPLOAD_PIECE_SIZE` pieces and counts each one as
+/// the HTTP client pulls it.
+fn counted_body(chunk: Bytes, counter: ByteCounter) -> Body {
+    let pieces: Vec<Bytes> = (0..chunk.len())
+        .step_by(UPLOAD_PIECE_SIZE)
+        .map(|offset| chunk.slice(offset..(offset + UPLOAD_PIECE_SIZE).min(chunk.len())))
+        .collect();
+    Body::wrap_stream(stream::iter(pieces).map(move |piece| {
+        counter.add(piece.len() as u64);
+        Ok::<_, Error>(piece)
+    }))
+}
+
+async fn upload_body(request: RequestBuilder, chunk: Bytes, counter: &ByteCounter) -> Result<(), Error> {
+    let length = chunk.len();
+    let response = request
+        .header(CONTENT_LENGTH, length)
+        .body(counted_body(chunk, counter.clone()))
+        .send()
+        .await;
+    match response {
+        Ok(resp) => {
+            if resp.status().is_success() {
+                Ok(())
+            } else {
+                Err(Error::other(format!("Request failed: {}", resp.status())))
+            }
+        }
+        Err(e) => {
+            Err(Error::other(format!("Request error: {}", e)))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct HttpTester {
     pub url: String,
+    pub settings: TransferSettings,
+    pub tls_url: Option<String>, // the same server over HTTPS, for HTTP/2 in the responsiveness test
 }
 
 impl HttpTester {
     pub fn new(url: &str) -> Self {
         HttpTester {
             url: url.to_string(),
+            ..Default::default()
         }
     }
 
-    pub fn set_url(&mut self, url: &str) {
-        self.url = url.to_string();
+    pub fn set_settings(&mut self, settings: TransferSettings) {
+        self.settings = settings;
+    }
+
+    pub fn set_tls_url(&mut self, url: Option<String>) {
+        self.tls_url = url;
     }
 
     pub async fn measure_latency(&self) -> Result<f64, Error> {
-        let client = Client::builder()
-            .timeout(Duration::from_secs(10))
-            .user_agent("Mozilla/5.0 (compatible; speedtest-tui/1.0)")
-            .build()
-            .expect("Failed to build Client");
+        time_request(build_client(Duration::from_secs(10)).head(self.url.as_str())).await
+    }
+
+    /// Times one HEAD request on a connection opened by hand, so DNS lookup,
+    /// TCP connect, TLS handshake and time to first byte can be told apart.
+    pub async fn measure_timing(&self) -> Result<HttpTimingBreakdown, Error> {
+        match timeout(TIMING_PROBE_TIMEOUT, self.measure_timing_inner()).await {
+            Ok(result) => result,
+            Err(_) => Err(Error::other("Timing probe timed out")),
+        }
+    }
+
+    async fn measure_timing_inner(&self) -> Result<HttpTimingBreakdown, Error> {
+        let url = reqwest::Url::parse(self.url.as_str())
+            .map_err(|e| Error::other(format!("Invalid url: {}", e)))?;
+        let host = url.host_str().ok_or_else(|| Error::other("Url has no host"))?;
+        let port = url.port_or_known_default().unwrap_or(80);
+        let host_header = match url.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_string(),
+        };
+        let path = match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_string(),
+        };
+        let request = format!(
+            "HEAD {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: {}\r\nConnection: close\r\n\r\n",
+            path, host_header, USER_AGENT
+        );
+
         let start = Instant::now();
-        let response = client.head(self.url.as_str()).send().await;
-        match response {
-            Ok(resp) => {
-                if resp.status().is_success() {
-                    let duration = start.elapsed();
-                    Ok(duration.as_millis() as f64)
-                } else {
-                    println!("{}: {}", resp.status(), resp.text().await.unwrap_or_default());
-                    Err(Error::new(std::io::ErrorKind::Other, "Request failed"))
-                }
-            }
-            Err(e) => {
-                Err(Error::new(std::io::ErrorKind::Other, format!("Request error: {}", e)))
-            }
-        } 
+        let address = lookup_host((host, port)).await?
+            .next()
+            .ok_or_else(|| Error::other(format!("No address found for {}", host)))?;
+        let dns = start.elapsed();
+
+        let connect_start = Instant::now();
+        let stream = TcpStream::connect(address).await?;
+        let connect = connect_start.elapsed();
+
+        let (tls, ttfb) = if url.scheme() == "https" {
+            let tls_start = Instant::now();
+            let connector = TlsConnector::from(native_tls::TlsConnector::new().map_err(Error::other)?);
+            let stream = connector.connect(host, stream).await.map_err(Error::other)?;
+            let tls = tls_start.elapsed();
+            (Some(tls), Self::time_first_byte(stream, request.as_bytes()).await?)
+        } else {
+            (None, Self::time_first_byte(stream, request.as_bytes()).await?)
+        };
+
+        Ok(HttpTimingBreakdown { dns, connect, tls, ttfb, total: start.elapsed() })
     }
 
-    pub async fn measure_latency_multiple(&self, count: u8) -> Result<HttpLatencyMeasurement, Error> {
-        let mut min = f64::MAX;
-        let mut max = f64::MIN;
-        let mut latency_total = 0.0;
-        let mut total_measurments: u8 = 0;
+    async fn time_first_byte<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, request: &[u8]) -> Result<Duration, Error> {
+        stream.write_all(request).await?;
+        let start = Instant::now();
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await?;
+        let ttfb = start.elapsed();
+        if &buf != b"HTTP/" {
+            return Err(Error::other("Invalid HTTP response"));
+        }
+        Ok(ttfb)
+    }
 
+    /// Runs `count` timing probes, skipping the ones that fail.
+    pub async fn measure_timings(&self, count: usize) -> Vec<HttpTimingBreakdown> {
+        let mut timings = Vec::with_capacity(count);
         for _ in 0..count {
-            match self.measure_latency().await {
-                Ok(latency) => {
-                    min = min.min(latency);
-                    max = max.max(latency);
-                    latency_total += latency;
-                    total_measurments += 1;
-                }
-                Err(e) => {
-                    Err(std::io::Error::new(std::io::ErrorKind::Other, format!("Latency measurement error: {}", e)))?;
-                },
+            if let Ok(timing) = self.measure_timing().await {
+                timings.push(timing);
             }
-            tokio::time::sleep(std::time::Duration::from_millis(300)).await; // Sleep to avoid overwhelming the server
+            tokio::time::sleep(Duration::from_millis(300)).await;
         }
+        timings
+    }
 
-        let avg = latency_total / total_measurments as f64;
-        Ok(HttpLatencyMeasurement { min, max, avg, total_measurments })
+    /// Downloads the `/speedtest/random{N}x{N}.jpg` images, adapting their
+    /// size from `HttpDownloadSize::min()` up to `HttpDownloadSize::max()`.
+    pub async fn measure_download(&self, progress: mpsc::UnboundedSender<HttpThroughputSample>) -> Result<HttpDownloadMeasurement, Error> {
+        let client = build_transfer_client(&self.settings);
+        let sizes: Vec<usize> = HttpDownloadSize::all()
+            .into_iter()
+            .filter(|size| *size >= HttpDownloadSize::min().to_size() && *size <= HttpDownloadSize::max().to_size())
+            .collect();
+        let base_url = self.url.clone();
+        download_phase(&client, &self.settings, sizes, progress, move |client, size| {
+            client.get(format!("{}/speedtest/random{}x{}.jpg", base_url, size, size))
+        }).await
     }
 
-    pub async fn measure_download(&self) -> Result<HttpDownloadMeasurement, Error> {
-        let client = Client::builder()
-            .timeout(Duration::from_secs(10))
-            .user_agent("Mozilla/5.0 (compatible; speedtest-tui/1.0)")
-            .build()
-            .expect("Failed to build Client");
-        let url = self.url.clone() + format!("/speedtest/random{}x{}.jpg", HttpDownloadSize::S3000.to_size(), HttpDownloadSize::S3000.to_size()).as_str();
+    /// Uploads to `/speedtest/upload.php`.
+    pub async fn measure_upload(&self, progress: mpsc::UnboundedSender<HttpThroughputSample>) -> Result<HttpUploadMeasurement, Error> {
+        let client = build_transfer_client(&self.settings);
+        let url = self.url.clone() + "/speedtest/upload.php";
+        upload_phase(&client, &self.settings, progress, move |client| client.post(url.as_str())).await
+    }
 
-        let start = Instant::now();
-        let response = client.get(url.as_str()).send().await;
-        
-        match response {
-            Ok(resp) => {
-                if resp.status().is_success() {
-                    let _body = resp.bytes().await.unwrap_or_default();
-                    let b = _body.len();
-                    let duration = start.elapsed();
-                    let bits = b as u64 * 8; // Convert bytes to bits
-                    let speed = bits as f64 / duration.as_secs_f64(); // bits per second
-                    return Ok(HttpDownloadMeasurement { bits, duration, speed })
-                } else {
-                    println!("{}: {}", resp.status(), resp.text().await.unwrap_or_default());
-                    Err(Error::new(std::io::ErrorKind::Other, "Request failed"))
-                }
-            }
-            Err(e) => {
-                Err(Error::new(std::io::ErrorKind::Other, format!("Request error: {}", e)))
-            }
-        }
-    } 
+    /// Loads the link with the largest `/speedtest/random{N}x{N}.jpg` image
+    /// and probes it with HEAD requests to `url`, both over `tls_url` when
+    /// there is one so the self probes can share the load over HTTP/2.
+    pub async fn measure_responsiveness(&self, progress: mpsc::UnboundedSender<HttpThroughputSample>) -> Result<ResponsivenessMeasurement, Error> {
+        let url = self.tls_url.clone().unwrap_or_else(|| self.url.clone());
+        let size = HttpDownloadSize::max().to_size();
+        let load_url = format!("{}/speedtest/random{}x{}.jpg", url, size, size);
+        let probe = HttpTester::new(url.as_str());
+        responsiveness_phase(&self.settings, probe, progress, move |client| client.get(load_url.as_str()), |client| client.head(url.as_str())).await
+    }
+}
 
-    pub async fn measure_upload(&self) -> Result<HttpUploadMeasurement, Error> {
-        let bytes = 10 * 1024 * 1024; // 10 MB of data
-        let client = Client::builder()
-            .timeout(Duration::from_secs(10))
-            .user_agent("Mozilla/5.0 (compatible; speedtest-tui/1.0)")
-            .build()
-            .expect("Failed to build Client");
-        
-        let url = self.url.clone() + "/speedtest/upload.php";
-        let data = vec![0u8; bytes];
-        let start = Instant::now();
-        
-        let response = client.post(url.as_str())
-            .body(data.clone())
-            .send()
-            .await;
-        
-        match response {
-            Ok(resp) => {
-                if resp.status().is_success() {
-                    let duration = start.elapsed();
-                    let bits = (bytes * 8) as u64;
-                    let speed = bits as f64 / duration.as_secs_f64();
-                    return Ok(HttpUploadMeasurement { bits, duration, speed })
-                } else {
-                    Err(Error::new(std::io::ErrorKind::Other, "Request failed"))
-                }
-            }
-            Err(e) => {
-                Err(Error::new(std::io::ErrorKind::Other, format!("Request error: {}", e)))
+impl SpeedTestBackend for HttpTester {
+    fn name(&self) -> &str {
+        "HTTP"
+    }
+
+    fn measure_latency(&self) -> BoxFuture<'_, Result<f64, Error>> {
+        Box::pin(HttpTester::measure_latency(self))
+    }
+
+    fn measure_timings(&self, count: usize) -> BoxFuture<'_, Vec<HttpTimingBreakdown>> {
+        Box::pin(HttpTester::measure_timings(self, count))
+    }
+
+    fn measure_download(&self, progress: mpsc::UnboundedSender<HttpThroughputSample>) -> BoxFuture<'_, Result<HttpDownloadMeasurement, Error>> {
+        Box::pin(HttpTester::measure_download(self, progress))
+    }
+
+    fn measure_upload(&self, progress: mpsc::UnboundedSender<HttpThroughputSample>) -> BoxFuture<'_, Result<HttpUploadMeasurement, Error>> {
+        Box::pin(HttpTester::measure_upload(self, progress))
+    }
+
+    fn measure_responsiveness(&self, progress: mpsc::UnboundedSender<HttpThroughputSample>) -> BoxFuture<'_, Result<ResponsivenessMeasurement, Error>> {
+        Box::pin(HttpTester::measure_responsiveness(self, progress))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn speeds(speeds: &[f64]) -> Vec<HttpThroughputSample> {
+        speeds.iter()
+            .enumerate()
+            .map(|(i, speed)| HttpThroughputSample { bits: 0, elapsed: SAMPLE_INTERVAL * (i as u32 + 1), speed: *speed })
+            .collect()
+    }
+
+    #[test]
+    fn percentiles_use_nearest_rank() {
+        let latency = HttpLatencyMeasurement::from_samples((1..=10).map(f64::from).collect(), 0);
+        assert_eq!(latency.median, 5.0);
+        assert_eq!(latency.p90, 9.0);
+        assert_eq!(latency.p99, 10.0);
+
+        let latency = HttpLatencyMeasurement::from_samples(vec![5.0, 1.0, 3.0, 2.0, 4.0], 0);
+        assert_eq!(latency.min, 1.0);
+        assert_eq!(latency.max, 5.0);
+        assert_eq!(latency.median, 3.0);
+        assert_eq!(latency.p90, 5.0);
+        assert_eq!(latency.p99, 5.0);
+    }
+
+    #[test]
+    fn jitter_is_mean_difference_of_consecutive_samples() {
+        let latency = HttpLatencyMeasurement::from_samples(vec![10.0, 20.0, 15.0], 0);
+        assert_eq!(latency.jitter, 7.5);
+        assert_eq!(latency.avg, 15.0);
+        // Samples keep the order they were measured in
+        assert_eq!(latency.samples, vec![10.0, 20.0, 15.0]);
+    }
+
+    #[test]
+    fn single_sample() {
+        let latency = HttpLatencyMeasurement::from_samples(vec![12.5], 1);
+        assert_eq!(latency.min, 12.5);
+        assert_eq!(latency.max, 12.5);
+        assert_eq!(latency.median, 12.5);
+        assert_eq!(latency.p90, 12.5);
+        assert_eq!(latency.p99, 12.5);
+        assert_eq!(latency.jitter, 0.0);
+        assert_eq!(latency.std_dev, 0.0);
+        assert_eq!(latency.sent(), 2);
+        assert_eq!(latency.loss_percent(), 50.0);
+    }
+
+    #[test]
+    fn empty_series_only_counts_losses() {
+        let latency = HttpLatencyMeasurement::from_samples(Vec::new(), 3);
+        assert_eq!(latency.total_measurments, 0);
+        assert_eq!(latency.lost, 3);
+        assert_eq!(latency.loss_percent(), 100.0);
+        assert_eq!(latency.avg, 0.0);
+
+        assert_eq!(HttpLatencyMeasurement::from_samples(Vec::new(), 0).loss_percent(), 0.0);
+    }
+
+    #[test]
+    fn trimmed_speed_skips_warmup() {
+        let samples = speeds(&[1.0, 100.0, 200.0, 300.0]);
+        // Only the last two samples end after 500 ms
+        assert_eq!(trimmed_speed(&samples, Duration::from_millis(500), 0.0), Some(250.0));
+    }
+
+    #[test]
+    fn trimmed_speed_without_samples_after_warmup() {
+        let samples = speeds(&[100.0, 200.0]);
+        assert_eq!(trimmed_speed(&samples, Duration::from_secs(10), 0.1), None);
+        assert_eq!(trimmed_speed(&[], Duration::ZERO, 0.1), None);
+    }
+
+    #[test]
+    fn trimmed_speed_trim_bounds() {
+        let samples = speeds(&[10.0, 20.0, 30.0, 40.0, 1000.0]);
+        assert_eq!(trimmed_speed(&samples, Duration::ZERO, 0.0), Some(220.0));
+        // Near or past one half the trim is capped so the middle sample is kept
+        assert_eq!(trimmed_speed(&samples, Duration::ZERO, 0.49), Some(30.0));
+        assert_eq!(trimmed_speed(&samples, Duration::ZERO, 0.5), Some(30.0));
+        // Two samples are too few to drop anything
+        assert_eq!(trimmed_speed(&speeds(&[10.0, 20.0]), Duration::ZERO, 0.49), Some(15.0));
+    }
+
+    /// HTTP server failing every other request with a 503 and answering
+    /// the rest with a 1 MB body, one request per connection.
+    async fn flaky() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let body = random_payload(1024 * 1024);
+        tokio::spawn(async move {
+            let served = Arc::new(AtomicUsize::new(0));
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let served = served.clone();
+                let body = body.clone();
+                tokio::spawn(async move {
+                    let mut head = Vec::new();
+                    let mut buf = [0u8; 4096];
+                    while !head.ends_with(b"\r\n\r\n") {
+                        match stream.read(&mut buf).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => head.extend_from_slice(&buf[..n]),
+                        }
+                    }
+                    // Upload bodies are drained by closing the connection
+                    let response = if served.fetch_add(1, Ordering::Relaxed).is_multiple_of(2) {
+                        b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_vec()
+                    } else {
+                        let mut response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len()).into_bytes();
+                        response.extend_from_slice(&body);
+                        response
+                    };
+                    let _ = stream.write_all(&response).await;
+                });
             }
+        });
+        format!("http://{}", address)
+    }
+
+    fn short_settings() -> TransferSettings {
+        TransferSettings {
+            connections: 2,
+            test_duration: Duration::from_millis(1500),
+            warmup: Duration::ZERO,
+            ..Default::default()
         }
     }
+
+    #[tokio::test]
+    async fn download_streams_retry_failed_requests() {
+        let url = flaky().await;
+        let settings = short_settings();
+        let download = download_phase(&build_transfer_client(&settings), &settings, vec![1024 * 1024], mpsc::unbounded_channel().0, move |client, _| client.get(url.as_str())).await.unwrap();
+        assert!(download.failed_requests > 0);
+        // Streams go on after their first failure, which would otherwise
+        // leave them a body each at most
+        assert!(download.bits >= 4 * 8 * 1024 * 1024);
+    }
+
+    #[tokio::test]
+    async fn download_without_answer_reports_the_error() {
+        // Bound and dropped, so nothing listens on the port
+        let address = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let settings = short_settings();
+        let url = format!("http://{}", address);
+        let download = download_phase(&build_transfer_client(&settings), &settings, vec![1024], mpsc::unbounded_channel().0, move |client, _| client.get(url.as_str())).await;
+        assert!(download.is_err());
+    }
 }
@@ -0,0 +1,154 @@
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{io::Error, net::{IpAddr, SocketAddr}, time::{Duration, Instant}};
+use tokio::{net::{lookup_host, UdpSocket}, time::timeout_at};
+
+use crate::http_tester::{latency_series, HttpLatencyMeasurement};
+
+const ECHO_TIMEOUT: Duration = Duration::from_secs(2);
+const ECHO_PAYLOAD: &[u8] = b"speedtest-tui ping";
+const ICMP_HEADER_SIZE: usize = 8;
+const ICMPV4_ECHO_REQUEST: u8 = 8;
+const ICMPV4_ECHO_REPLY: u8 = 0;
+const ICMPV6_ECHO_REQUEST: u8 = 128;
+const ICMPV6_ECHO_REPLY: u8 = 129;
+
+/// ICMP echo round trips to `host`, which leave out the HTTP overhead and
+/// server processing of the HTTP latency probes.
+#[derive(Debug, Clone)]
+pub struct IcmpTester {
+    pub host: String,
+}
+
+/// An ICMP socket opened for one series of probes. Unprivileged datagram
+/// sockets leave the identifier and checksum to the kernel and only deliver
+/// our own replies; raw sockets need both checked by hand.
+struct IcmpSocket {
+    socket: UdpSocket,
+    address: SocketAddr,
+    raw: bool,
+    identifier: u16,
+}
+
+impl IcmpTester {
+    pub fn new(host: &str) -> Self {
+        IcmpTester { host: host.to_string() }
+    }
+
+    /// Host name or address in `target`, which may be a URL or a
+    /// `host:port` like the ones the backends are built from.
+    pub fn host_of(target: &str) -> String {
+        if let Ok(url) = reqwest::Url::parse(target)
+            && let Some(host) = url.host_str() {
+            return host.trim_start_matches('[').trim_end_matches(']').to_string();
+        }
+        if let Ok(address) = target.parse::<SocketAddr>() {
+            return address.ip().to_string();
+        }
+        match target.rsplit_once(':') {
+            Some((host, port)) if port.parse::<u16>().is_ok() && !host.contains(':') => host.to_string(),
+            _ => target.to_string(),
+        }
+    }
+
+    async fn open(&self) -> Result<IcmpSocket, Error> {
+        let address = lookup_host((self.host.as_str(), 0)).await?
+            .next()
+            .ok_or_else(|| Error::other(format!("No address found for {}", self.host)))?;
+        let (domain, protocol) = match address.ip() {
+            IpAddr::V4(_) => (Domain::IPV4, Protocol::ICMPV4),
+            IpAddr::V6(_) => (Domain::IPV6, Protocol::ICMPV6),
+        };
+        // Datagram ICMP needs the group in net.ipv4.ping_group_range, raw needs CAP_NET_RAW
+        let (socket, raw) = match Socket::new(domain, Type::DGRAM, Some(protocol)) {
+            Ok(socket) => (socket, false),
+            Err(_) => match Socket::new(domain, Type::RAW, Some(protocol)) {
+                Ok(socket) => (socket, true),
+                Err(e) => return Err(Error::new(e.kind(), format!("ICMP sockets not permitted: {}", e))),
+            },
+        };
+        socket.set_nonblocking(true)?;
+        let socket = UdpSocket::from_std(std::net::UdpSocket::from(socket))?;
+        Ok(IcmpSocket { socket, address, raw, identifier: rand::random() })
+    }
+
+    /// Runs `count` echo probes over one socket, failing only when no ICMP
+    /// socket can be opened at all.
+    pub async fn measure_latency_multiple(&self, count: usize) -> Result<HttpLatencyMeasurement, Error> {
+        let socket = self.open().await?;
+        let mut sequence = 0u16;
+        let socket = &socket;
+        Ok(latency_series(count, || {
+            sequence = sequence.wrapping_add(1);
+            socket.ping(sequence)
+        }).await)
+    }
+}
+
+impl IcmpSocket {
+    fn checksum(data: &[u8]) -> u16 {
+        let mut sum: u32 = data.chunks(2)
+            .map(|pair| u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)]) as u32)
+            .sum();
+        while sum > 0xffff {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+        !(sum as u16)
+    }
+
+    fn echo_request(&self, sequence: u16) -> Vec<u8> {
+        let request_type = if self.address.is_ipv4() { ICMPV4_ECHO_REQUEST } else { ICMPV6_ECHO_REQUEST };
+        let mut packet = vec![request_type, 0, 0, 0];
+        packet.extend_from_slice(&self.identifier.to_be_bytes());
+        packet.extend_from_slice(&sequence.to_be_bytes());
+        packet.extend_from_slice(ECHO_PAYLOAD);
+        // The kernel fills in the ICMPv6 checksum itself
+        if self.address.is_ipv4() {
+            let checksum = Self::checksum(&packet);
+            packet[2..4].copy_from_slice(&checksum.to_be_bytes());
+        }
+        packet
+    }
+
+    /// Whether `packet` is the reply to our request number `sequence`.
+    fn is_reply(&self, packet: &[u8], sequence: u16) -> bool {
+        // Raw IPv4 sockets hand over the IP header too
+        let icmp = if self.raw && self.address.is_ipv4() {
+            let header_size = match packet.first() {
+                Some(first) => ((first & 0x0f) as usize) * 4,
+                None => return false,
+            };
+            match packet.get(header_size..) {
+                Some(icmp) => icmp,
+                None => return false,
+            }
+        } else {
+            packet
+        };
+        if icmp.len() < ICMP_HEADER_SIZE {
+            return false;
+        }
+        let reply_type = if self.address.is_ipv4() { ICMPV4_ECHO_REPLY } else { ICMPV6_ECHO_REPLY };
+        let identifier = u16::from_be_bytes([icmp[4], icmp[5]]);
+        let reply_sequence = u16::from_be_bytes([icmp[6], icmp[7]]);
+        icmp[0] == reply_type && reply_sequence == sequence && (!self.raw || identifier == self.identifier)
+    }
+
+    async fn ping(&self, sequence: u16) -> Result<f64, Error> {
+        let request = self.echo_request(sequence);
+        let start = Instant::now();
+        let deadline = start + ECHO_TIMEOUT;
+        self.socket.send_to(&request, self.address).await?;
+        let mut buf = [0u8; 1024];
+        loop {
+            match timeout_at(deadline.into(), self.socket.recv_from(&mut buf)).await {
+                Ok(Ok((len, _))) => {
+                    if self.is_reply(&buf[..len], sequence) {
+                        return Ok(start.elapsed().as_secs_f64() * 1000.0);
+                    }
+                }
+                Ok(Err(e)) => return Err(e),
+                Err(_) => return Err(Error::other("Echo request timed out")),
+            }
+        }
+    }
+}
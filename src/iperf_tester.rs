@@ -0,0 +1,418 @@
+use futures_util::future::BoxFuture;
+use rand::Rng;
+use serde_json::json;
+use std::{io::Error, sync::Arc, time::{Duration, Instant}};
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream, sync::{mpsc, RwLock}, task::JoinSet, time::{timeout, timeout_at}};
+
+use crate::{backend::SpeedTestBackend, http_tester::{random_payload, ByteCounter, HttpDownloadMeasurement, HttpThroughputSample, HttpUploadMeasurement, ThroughputMeter, TransferSettings}};
+
+const DEFAULT_PORT: u16 = 5201;
+const CONTROL_TIMEOUT: Duration = Duration::from_secs(10);
+/// Size of each read and write on the data streams, iperf3's TCP default.
+const BLOCK_SIZE: usize = 128 * 1024;
+/// 36 characters and the terminating NUL.
+const COOKIE_SIZE: usize = 37;
+const COOKIE_CHARS: &[u8] = b"abcdefghijklmnopqrstuvwxyz234567";
+/// Upper bound for a JSON message from the server, which only ever sends
+/// small ones.
+const MAX_JSON_SIZE: u32 = 1024 * 1024;
+
+// States the server moves the test through on the control connection
+const TEST_START: i8 = 1;
+const TEST_RUNNING: i8 = 2;
+const TEST_END: i8 = 4;
+const PARAM_EXCHANGE: i8 = 9;
+const CREATE_STREAMS: i8 = 10;
+const SERVER_TERMINATE: i8 = 11;
+const EXCHANGE_RESULTS: i8 = 13;
+const DISPLAY_RESULTS: i8 = 14;
+const IPERF_DONE: i8 = 16;
+const ACCESS_DENIED: i8 = -1;
+const SERVER_ERROR: i8 = -2;
+
+/// Client for an `iperf3 -s` server. Each transfer phase is one iperf3 TCP
+/// test with `connections` parallel streams, in reverse mode for download
+/// so the server is the sender.
+///
+/// iperf3 has no ping, so latency is the time the TCP handshake with the
+/// server port takes. Probes wait while a test is creating its streams,
+/// since the server would take a probe for one of them.
+#[derive(Debug, Clone)]
+pub struct IperfTester {
+    pub host: String, // host:port
+    pub settings: TransferSettings,
+    stream_setup: Arc<RwLock<()>>,
+}
+
+/// Data connections of a running test, with the id the server gave each.
+struct IperfStreams {
+    control: TcpStream,
+    streams: Vec<(usize, TcpStream)>,
+}
+
+impl IperfTester {
+    /// Creates a tester for `host`, using iperf3's port when it has none.
+    pub fn new(host: &str, settings: TransferSettings) -> Self {
+        let host = if host.contains(':') {
+            host.to_string()
+        } else {
+            format!("{}:{}", host, DEFAULT_PORT)
+        };
+        IperfTester {
+            host,
+            settings,
+            stream_setup: Arc::new(RwLock::new(())),
+        }
+    }
+
+    async fn connect(&self) -> Result<TcpStream, Error> {
+        let stream = match timeout(CONTROL_TIMEOUT, TcpStream::connect(self.host.as_str())).await {
+            Ok(stream) => stream?,
+            Err(_) => return Err(Error::other(format!("Connection to {} timed out", self.host))),
+        };
+        stream.set_nodelay(true)?;
+        Ok(stream)
+    }
+
+    pub async fn measure_latency(&self) -> Result<f64, Error> {
+        let _setup = self.stream_setup.read().await;
+        let start = Instant::now();
+        let stream = self.connect().await?;
+        let duration = start.elapsed();
+        drop(stream);
+        Ok(duration.as_secs_f64() * 1000.0)
+    }
+
+    fn cookie() -> Vec<u8> {
+        let mut rng = rand::rng();
+        let mut cookie: Vec<u8> = (0..COOKIE_SIZE - 1)
+            .map(|_| COOKIE_CHARS[rng.random_range(0..COOKIE_CHARS.len())])
+            .collect();
+        cookie.push(0);
+        cookie
+    }
+
+    async fn read_state(control: &mut TcpStream) -> Result<i8, Error> {
+        let state = match timeout(CONTROL_TIMEOUT, control.read_i8()).await {
+            Ok(state) => state?,
+            Err(_) => return Err(Error::other("Timed out waiting for the server")),
+        };
+        match state {
+            ACCESS_DENIED => Err(Error::other("Server is busy running another test")),
+            SERVER_ERROR => {
+                let code = control.read_i32().await.unwrap_or_default();
+                let errno = control.read_i32().await.unwrap_or_default();
+                Err(Error::other(format!("Server error {} (errno {})", code, errno)))
+            }
+            SERVER_TERMINATE => Err(Error::other("Server terminated the test")),
+            state => Ok(state),
+        }
+    }
+
+    async fn expect_state(control: &mut TcpStream, expected: i8) -> Result<(), Error> {
+        let state = Self::read_state(control).await?;
+        if state != expected {
+            return Err(Error::other(format!("Unexpected server state {} (expected {})", state, expected)));
+        }
+        Ok(())
+    }
+
+    /// Sends `value` the way iperf3 frames JSON: its length as a 32 bit big
+    /// endian number, then the text.
+    async fn write_json(control: &mut TcpStream, value: &serde_json::Value) -> Result<(), Error> {
+        let text = value.to_string();
+        control.write_u32(text.len() as u32).await?;
+        control.write_all(text.as_bytes()).await?;
+        Ok(())
+    }
+
+    async fn read_json(control: &mut TcpStream) -> Result<serde_json::Value, Error> {
+        let size = control.read_u32().await?;
+        if size > MAX_JSON_SIZE {
+            return Err(Error::other(format!("Server sent a {} byte message", size)));
+        }
+        let mut text = vec![0u8; size as usize];
+        control.read_exact(&mut text).await?;
+        serde_json::from_slice(&text).map_err(Error::other)
+    }
+
+    /// Runs the control handshake up to `TEST_RUNNING`: cookie, parameters
+    /// and one data connection per stream, each introduced by the cookie.
+    async fn start_test(&self, reverse: bool) -> Result<IperfStreams, Error> {
+        let _setup = self.stream_setup.write().await;
+        let cookie = Self::cookie();
+        let mut control = self.connect().await?;
+        control.write_all(&cookie).await?;
+        Self::expect_state(&mut control, PARAM_EXCHANGE).await?;
+
+        let connections = self.settings.connections.max(1);
+        let mut params = json!({
+            "tcp": true,
+            "omit": 0,
+            "time": self.settings.test_duration.as_secs_f64().ceil() as u64,
+            "parallel": connections,
+            "len": BLOCK_SIZE,
+            "pacing_timer": 1000,
+            "client_version": "3.12",
+        });
+        // Like iperf3 itself only send the key when set, older servers take
+        // its presence alone for reverse mode
+        if reverse {
+            params["reverse"] = json!(true);
+        }
+        Self::write_json(&mut control, &params).await?;
+        Self::expect_state(&mut control, CREATE_STREAMS).await?;
+
+        let mut streams = Vec::with_capacity(connections);
+        for index in 0..connections {
+            let mut stream = self.connect().await?;
+            stream.write_all(&cookie).await?;
+            // iperf3 numbers the first stream 1 and the following ones from 3
+            let id = if index == 0 { 1 } else { index + 2 };
+            streams.push((id, stream));
+        }
+
+        Self::expect_state(&mut control, TEST_START).await?;
+        Self::expect_state(&mut control, TEST_RUNNING).await?;
+        Ok(IperfStreams { control, streams })
+    }
+
+    /// Moves data on every stream until `test_duration` runs out, reading
+    /// in reverse mode and writing otherwise. The streams are handed back
+    /// with the bytes each one moved, as they have to stay open until the
+    /// server has its results.
+    async fn transfer(&self, streams: Vec<(usize, TcpStream)>, reverse: bool, counter: ByteCounter) -> (Vec<(usize, TcpStream, u64)>, Option<Error>) {
+        let deadline = Instant::now() + self.settings.test_duration;
+        let payload = random_payload(BLOCK_SIZE);
+        let mut tasks = JoinSet::new();
+        for (id, mut stream) in streams {
+            let counter = counter.clone();
+            let payload = payload.clone();
+            tasks.spawn(async move {
+                let mut buf = vec![0u8; BLOCK_SIZE];
+                let mut bytes = 0;
+                while Instant::now() < deadline {
+                    let moved = if reverse {
+                        timeout_at(deadline.into(), stream.read(&mut buf)).await
+                    } else {
+                        timeout_at(deadline.into(), stream.write(&payload)).await
+                    };
+                    match moved {
+                        Ok(Ok(0)) => return Err(Error::other("Connection closed by server")),
+                        Ok(Ok(n)) => {
+                            counter.add(n as u64);
+                            bytes += n as u64;
+                        }
+                        Ok(Err(e)) => return Err(e),
+                        Err(_) => break,
+                    }
+                }
+                Ok((id, stream, bytes))
+            });
+        }
+
+        let mut finished = Vec::new();
+        let mut last_error = None;
+        while let Some(result) = tasks.join_next().await {
+            match result {
+                Ok(Ok(stream)) => finished.push(stream),
+                Ok(Err(e)) => last_error = Some(e),
+                Err(e) => last_error = Some(Error::other(format!("Stream task failed: {}", e))),
+            }
+        }
+        finished.sort_by_key(|(id, _, _)| *id);
+        (finished, last_error)
+    }
+
+    /// Ends the test and trades results with the server, which needs ours
+    /// before it lets the test finish cleanly.
+    async fn end_test(control: &mut TcpStream, streams: &[(usize, TcpStream, u64)], duration: Duration, reverse: bool) -> Result<(), Error> {
+        control.write_i8(TEST_END).await?;
+        Self::expect_state(control, EXCHANGE_RESULTS).await?;
+
+        let streams: Vec<serde_json::Value> = streams.iter()
+            .map(|(id, _, bytes)| json!({
+                "id": id,
+                "bytes": bytes,
+                "retransmits": -1,
+                "jitter": 0,
+                "errors": 0,
+                "omitted_errors": 0,
+                "packets": 0,
+                "start_time": 0,
+                "end_time": duration.as_secs_f64(),
+            }))
+            .collect();
+        let results = json!({
+            "cpu_util_total": 0,
+            "cpu_util_user": 0,
+            "cpu_util_system": 0,
+            "sender_has_retransmits": if reverse { -1 } else { 0 },
+            "streams": streams,
+        });
+        Self::write_json(control, &results).await?;
+        Self::read_json(control).await?;
+
+        Self::expect_state(control, DISPLAY_RESULTS).await?;
+        control.write_i8(IPERF_DONE).await?;
+        Ok(())
+    }
+
+    /// Runs one test and returns the bytes moved, the time it took, the
+    /// throughput samples and the error that cut it short, if any.
+    async fn run_test(&self, reverse: bool, progress: mpsc::UnboundedSender<HttpThroughputSample>) -> Result<(u64, Duration, Vec<HttpThroughputSample>, Option<Error>), Error> {
+        let IperfStreams { mut control, streams } = self.start_test(reverse).await?;
+
+        let meter = ThroughputMeter::start(progress);
+        let start = Instant::now();
+        let (streams, error) = self.transfer(streams, reverse, meter.counter()).await;
+        let duration = start.elapsed().min(self.settings.test_duration);
+        let (bytes, samples) = meter.finish().await;
+
+        let error = match Self::end_test(&mut control, &streams, duration, reverse).await {
+            Ok(()) => error,
+            Err(e) => error.or(Some(e)),
+        };
+        Ok((bytes, duration, samples, error))
+    }
+
+    pub async fn measure_download(&self, progress: mpsc::UnboundedSender<HttpThroughputSample>) -> Result<HttpDownloadMeasurement, Error> {
+        let (bytes, duration, samples, error) = self.run_test(true, progress).await?;
+        HttpDownloadMeasurement::from_transfer(bytes, duration, samples, self.settings.warmup, self.settings.trim_fraction, error)
+    }
+
+    pub async fn measure_upload(&self, progress: mpsc::UnboundedSender<HttpThroughputSample>) -> Result<HttpUploadMeasurement, Error> {
+        let (bytes, duration, samples, error) = self.run_test(false, progress).await?;
+        HttpUploadMeasurement::from_transfer(bytes, duration, samples, self.settings.warmup, self.settings.trim_fraction, error)
+    }
+}
+
+impl SpeedTestBackend for IperfTester {
+    fn name(&self) -> &str {
+        "iperf3"
+    }
+
+    fn measure_latency(&self) -> BoxFuture<'_, Result<f64, Error>> {
+        Box::pin(IperfTester::measure_latency(self))
+    }
+
+    fn measure_download(&self, progress: mpsc::UnboundedSender<HttpThroughputSample>) -> BoxFuture<'_, Result<HttpDownloadMeasurement, Error>> {
+        Box::pin(IperfTester::measure_download(self, progress))
+    }
+
+    fn measure_upload(&self, progress: mpsc::UnboundedSender<HttpThroughputSample>) -> BoxFuture<'_, Result<HttpUploadMeasurement, Error>> {
+        Box::pin(IperfTester::measure_upload(self, progress))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use tokio::net::TcpListener;
+
+    /// Parameters and results a stand-in server got from one test.
+    #[derive(Debug, Default, Clone)]
+    struct Recorded {
+        params: serde_json::Value,
+        results: serde_json::Value,
+    }
+
+    /// Minimal iperf3 server running one test at a time through the same
+    /// states as the real one, sending data in reverse mode and draining
+    /// it otherwise.
+    async fn stand_in() -> (String, Arc<Mutex<Vec<Recorded>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let tests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = tests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut control, _) = listener.accept().await.unwrap();
+                let mut cookie = [0u8; COOKIE_SIZE];
+                control.read_exact(&mut cookie).await.unwrap();
+                control.write_i8(PARAM_EXCHANGE).await.unwrap();
+                let params = IperfTester::read_json(&mut control).await.unwrap();
+                let reverse = params.get("reverse").is_some();
+
+                control.write_i8(CREATE_STREAMS).await.unwrap();
+                for _ in 0..params["parallel"].as_u64().unwrap() {
+                    let (mut stream, _) = listener.accept().await.unwrap();
+                    let mut stream_cookie = [0u8; COOKIE_SIZE];
+                    stream.read_exact(&mut stream_cookie).await.unwrap();
+                    assert_eq!(stream_cookie, cookie);
+                    tokio::spawn(async move {
+                        let mut buf = vec![0u8; BLOCK_SIZE];
+                        loop {
+                            let moved = if reverse { stream.write(&buf).await } else { stream.read(&mut buf).await };
+                            if !matches!(moved, Ok(n) if n > 0) {
+                                return;
+                            }
+                        }
+                    });
+                }
+                control.write_i8(TEST_START).await.unwrap();
+                control.write_i8(TEST_RUNNING).await.unwrap();
+
+                assert_eq!(control.read_i8().await.unwrap(), TEST_END);
+                control.write_i8(EXCHANGE_RESULTS).await.unwrap();
+                let results = IperfTester::read_json(&mut control).await.unwrap();
+                // Recorded before the client is let go
+                recorded.lock().unwrap().push(Recorded { params, results });
+                IperfTester::write_json(&mut control, &json!({ "streams": [] })).await.unwrap();
+                control.write_i8(DISPLAY_RESULTS).await.unwrap();
+                assert_eq!(control.read_i8().await.unwrap(), IPERF_DONE);
+            }
+        });
+        (address, tests)
+    }
+
+    fn tester(host: &str) -> IperfTester {
+        IperfTester::new(host, TransferSettings {
+            connections: 3,
+            test_duration: Duration::from_millis(1500),
+            warmup: Duration::ZERO,
+            ..Default::default()
+        })
+    }
+
+    fn stream_ids(results: &serde_json::Value) -> Vec<u64> {
+        results["streams"].as_array().unwrap().iter().map(|stream| stream["id"].as_u64().unwrap()).collect()
+    }
+
+    #[test]
+    fn adds_default_port() {
+        assert_eq!(IperfTester::new("example.com", TransferSettings::default()).host, "example.com:5201");
+        assert_eq!(IperfTester::new("example.com:5202", TransferSettings::default()).host, "example.com:5202");
+    }
+
+    #[tokio::test]
+    async fn downloads_in_reverse_mode() {
+        let (address, tests) = stand_in().await;
+        let download = tester(address.as_str()).measure_download(mpsc::unbounded_channel().0).await.unwrap();
+        assert!(download.bits > 0);
+
+        let test = tests.lock().unwrap()[0].clone();
+        assert_eq!(test.params["reverse"], json!(true));
+        assert_eq!(test.params["parallel"], json!(3));
+        assert_eq!(test.params["time"], json!(2));
+        assert_eq!(test.params["len"], json!(BLOCK_SIZE));
+        assert_eq!(stream_ids(&test.results), vec![1, 3, 4]);
+    }
+
+    #[tokio::test]
+    async fn uploads_without_reverse_key() {
+        let (address, tests) = stand_in().await;
+        let upload = tester(address.as_str()).measure_upload(mpsc::unbounded_channel().0).await.unwrap();
+        assert!(upload.bits > 0);
+
+        let test = tests.lock().unwrap()[0].clone();
+        assert!(test.params.get("reverse").is_none());
+        assert!(test.params.get("num").is_none());
+        assert!(test.params.get("blockcount").is_none());
+        assert_eq!(stream_ids(&test.results), vec![1, 3, 4]);
+        // The bytes reported per stream add up to what was measured
+        let bytes: u64 = test.results["streams"].as_array().unwrap().iter().map(|stream| stream["bytes"].as_u64().unwrap()).sum();
+        assert_eq!(bytes * 8, upload.bits);
+    }
+}
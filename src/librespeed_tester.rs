@@ -0,0 +1,133 @@
+use futures_util::future::BoxFuture;
+use std::{io::Error, time::Duration};
+use tokio::sync::mpsc;
+
+use crate::{backend::SpeedTestBackend, http_tester::{build_client, build_transfer_client, time_request, download_phase, upload_phase, HttpDownloadMeasurement, HttpTester, HttpThroughputSample, HttpTimingBreakdown, HttpUploadMeasurement, TransferSettings}, responsiveness::{responsiveness_phase, ResponsivenessMeasurement}};
+
+/// `garbage.php` sizes its reply in chunks of one MiB.
+const GARBAGE_CHUNK: usize = 1024 * 1024;
+const GARBAGE_CHUNK_COUNTS: [usize; 7] = [1, 2, 4, 8, 16, 32, 64];
+
+/// Client for a self-hosted LibreSpeed backend, `url` being the directory
+/// that holds its scripts:
+///
+/// - `garbage.php?ckSize=<n>` replies with `n` MiB of random data
+/// - `empty.php` replies with nothing, to a GET for ping and to a POST for upload
+/// - `getIP.php?isp=true` replies with the client IP and ISP as JSON
+#[derive(Debug, Clone)]
+pub struct LibreSpeedTester {
+    pub url: String,
+    pub settings: TransferSettings,
+}
+
+impl LibreSpeedTester {
+    pub fn new(url: &str, settings: TransferSettings) -> Self {
+        LibreSpeedTester {
+            url: url.trim_end_matches('/').to_string(),
+            settings,
+        }
+    }
+
+    /// Random query parameter LibreSpeed clients add so no cache answers
+    /// in place of the server.
+    fn cache_buster() -> f64 {
+        rand::random::<f64>()
+    }
+
+    pub async fn measure_latency(&self) -> Result<f64, Error> {
+        let url = format!("{}/empty.php?cors=true&r={}", self.url, Self::cache_buster());
+        time_request(build_client(Duration::from_secs(10)).get(url)).await
+    }
+
+    /// Downloads `garbage.php`, adapting `ckSize` along `GARBAGE_CHUNK_COUNTS`.
+    pub async fn measure_download(&self, progress: mpsc::UnboundedSender<HttpThroughputSample>) -> Result<HttpDownloadMeasurement, Error> {
+        let client = build_transfer_client(&self.settings);
+        let sizes = GARBAGE_CHUNK_COUNTS.iter().map(|count| count * GARBAGE_CHUNK).collect();
+        let base_url = self.url.clone();
+        download_phase(&client, &self.settings, sizes, progress, move |client, size| {
+            client.get(format!("{}/garbage.php?cors=true&r={}&ckSize={}", base_url, Self::cache_buster(), size / GARBAGE_CHUNK))
+        }).await
+    }
+
+    /// Uploads to `empty.php`, which discards the body.
+    pub async fn measure_upload(&self, progress: mpsc::UnboundedSender<HttpThroughputSample>) -> Result<HttpUploadMeasurement, Error> {
+        let client = build_transfer_client(&self.settings);
+        let base_url = self.url.clone();
+        upload_phase(&client, &self.settings, progress, move |client| {
+            client.post(format!("{}/empty.php?cors=true&r={}", base_url, Self::cache_buster()))
+        }).await
+    }
+
+    /// Loads the link with the largest `garbage.php` reply and probes it
+    /// with `empty.php`.
+    pub async fn measure_responsiveness(&self, progress: mpsc::UnboundedSender<HttpThroughputSample>) -> Result<ResponsivenessMeasurement, Error> {
+        let probe = HttpTester::new(format!("{}/empty.php", self.url).as_str());
+        let base_url = self.url.clone();
+        let count = GARBAGE_CHUNK_COUNTS[GARBAGE_CHUNK_COUNTS.len() - 1];
+        responsiveness_phase(&self.settings, probe, progress, move |client| {
+            client.get(format!("{}/garbage.php?cors=true&r={}&ckSize={}", base_url, Self::cache_buster(), count))
+        }, |client| {
+            client.get(format!("{}/empty.php?cors=true&r={}", self.url, Self::cache_buster()))
+        }).await
+    }
+
+    /// Client IP and ISP as `getIP.php` describes them, e.g.
+    /// `203.0.113.7 - Example ISP, AR`.
+    pub async fn client_info(&self) -> Result<String, Error> {
+        let client = build_client(Duration::from_secs(10));
+        let url = format!("{}/getIP.php?isp=true&r={}", self.url, Self::cache_buster());
+        let resp = match client.get(url).send().await {
+            Ok(resp) => resp,
+            Err(e) => return Err(Error::other(format!("Request error: {}", e))),
+        };
+        if !resp.status().is_success() {
+            return Err(Error::other(format!("Request failed: {}", resp.status())));
+        }
+        let body = match resp.text().await {
+            Ok(body) => body,
+            Err(e) => return Err(Error::other(format!("Request error: {}", e))),
+        };
+        // Older backends reply with the bare IP instead of JSON
+        match serde_json::from_str::<serde_json::Value>(body.as_str()) {
+            Ok(json) => match json.get("processedString").and_then(|value| value.as_str()) {
+                Some(info) => Ok(info.to_string()),
+                None => Err(Error::other("Missing processedString in getIP.php reply")),
+            },
+            Err(_) => Ok(body.trim().to_string()),
+        }
+    }
+}
+
+impl SpeedTestBackend for LibreSpeedTester {
+    fn name(&self) -> &str {
+        "LibreSpeed"
+    }
+
+    fn measure_latency(&self) -> BoxFuture<'_, Result<f64, Error>> {
+        Box::pin(LibreSpeedTester::measure_latency(self))
+    }
+
+    /// Timing probes are HEAD requests to `empty.php`.
+    fn measure_timings(&self, count: usize) -> BoxFuture<'_, Vec<HttpTimingBreakdown>> {
+        Box::pin(async move {
+            let probe = HttpTester::new(format!("{}/empty.php", self.url).as_str());
+            probe.measure_timings(count).await
+        })
+    }
+
+    fn measure_download(&self, progress: mpsc::UnboundedSender<HttpThroughputSample>) -> BoxFuture<'_, Result<HttpDownloadMeasurement, Error>> {
+        Box::pin(LibreSpeedTester::measure_download(self, progress))
+    }
+
+    fn measure_upload(&self, progress: mpsc::UnboundedSender<HttpThroughputSample>) -> BoxFuture<'_, Result<HttpUploadMeasurement, Error>> {
+        Box::pin(LibreSpeedTester::measure_upload(self, progress))
+    }
+
+    fn measure_responsiveness(&self, progress: mpsc::UnboundedSender<HttpThroughputSample>) -> BoxFuture<'_, Result<ResponsivenessMeasurement, Error>> {
+        Box::pin(LibreSpeedTester::measure_responsiveness(self, progress))
+    }
+
+    fn client_info(&self) -> BoxFuture<'_, Option<String>> {
+        Box::pin(async { LibreSpeedTester::client_info(self).await.ok() })
+    }
+}
@@ -5,13 +5,36 @@ mod download_component;
 mod upload_component;
 mod http_tester;
 mod services;
+mod cli;
+mod timing_component;
+mod tcp_tester;
+mod backend;
+mod librespeed_tester;
+mod custom_tester;
+mod iperf_tester;
+mod serve;
+mod udp_tester;
+mod udp_component;
+mod icmp_tester;
+mod responsiveness;
+mod server_selection;
+mod selection_component;
+mod picker_component;
+mod traceroute;
+mod traceroute_component;
 use app::App;
+use clap::Parser;
+use cli::{Cli, Command};
 
 #[tokio::main]
 async fn main() -> color_eyre::Result<()> {
     color_eyre::install()?;
+    let cli = Cli::parse();
+    if let Some(Command::Serve(args)) = &cli.command {
+        return Ok(serve::run(args).await?);
+    }
     let terminal = ratatui::init();
-    let result = App::new().run(terminal).await;
+    let result = App::new(cli).run(terminal).await;
     ratatui::restore();
-    Ok(())
+    result
 }
\ No newline at end of file
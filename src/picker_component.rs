@@ -0,0 +1,103 @@
+use std::collections::HashMap;
+
+use ratatui::{layout::Constraint, style::{Color, Style, Stylize}, text::Line, widgets::{Block, Row, StatefulWidget, Table, TableState, Widget}};
+
+use crate::servers::Server;
+
+/// Server list that can be filtered by typing and scrolled to pick the
+/// server to test against.
+#[derive(Default, Clone)]
+pub struct PickerComponent {
+    servers: Vec<Server>,
+    latencies: HashMap<u32, f64>, // last median latency in ms by server id
+    skipped: usize, // malformed entries left out of the list
+    filter: String,
+    selected: usize, // index into the filtered servers
+}
+
+impl PickerComponent {
+    pub fn set_servers(&mut self, servers: Vec<Server>) {
+        self.servers = servers;
+        self.selected = 0;
+    }
+    pub fn set_skipped(&mut self, skipped: usize) {
+        self.skipped = skipped;
+    }
+    pub fn set_latency(&mut self, id: u32, latency: f64) {
+        self.latencies.insert(id, latency);
+    }
+    pub fn push_filter(&mut self, c: char) {
+        self.filter.push(c);
+        self.selected = 0;
+    }
+    pub fn pop_filter(&mut self) {
+        self.filter.pop();
+        self.selected = 0;
+    }
+
+    /// Moves the selection by `delta` rows, stopping at either end.
+    pub fn move_selection(&mut self, delta: isize) {
+        let last = self.filtered().len().saturating_sub(1);
+        self.selected = self.selected.saturating_add_signed(delta).min(last);
+    }
+
+    /// Servers whose sponsor, city, country or host contain the filter,
+    /// ignoring case.
+    fn filtered(&self) -> Vec<&Server> {
+        let filter = self.filter.to_lowercase();
+        self.servers.iter()
+            .filter(|server| {
+                [&server.sponsor, &server.name, &server.country, &server.cc, &server.host]
+                    .iter()
+                    .any(|field| field.to_lowercase().contains(filter.as_str()))
+            })
+            .collect()
+    }
+
+    pub fn selected(&self) -> Option<&Server> {
+        self.filtered().get(self.selected).copied()
+    }
+
+    fn row(&self, server: &Server) -> Row<'static> {
+        let distance = server.distance.map_or("-".to_string(), |distance| format!("{:.0} km", distance));
+        let latency = self.latencies.get(&server.id).map_or("-".to_string(), |latency| format!("{:.2} ms", latency));
+        Row::new(vec![
+            server.sponsor.clone(),
+            server.name.clone(),
+            server.country.clone(),
+            distance,
+            latency,
+        ])
+    }
+}
+
+impl Widget for &PickerComponent {
+    fn render(self, area: ratatui::layout::Rect, buf: &mut ratatui::buffer::Buffer) {
+        let servers = self.filtered();
+        let header = Row::new(vec!["Sponsor", "City", "Country", "Distance", "Latency"]).bold();
+        let rows: Vec<Row> = servers.iter().map(|server| self.row(server)).collect();
+        let widths = [
+            Constraint::Min(20),
+            Constraint::Min(14),
+            Constraint::Min(12),
+            Constraint::Length(10),
+            Constraint::Length(10),
+        ];
+
+        let block = Block::bordered()
+            .border_style(Style::default().fg(Color::Green))
+            .title(Line::from(format!("Servers ({}/{}) - filter: {}", servers.len(), self.servers.len(), self.filter)).bold())
+            .title(Line::from(if self.skipped > 0 { format!("{} malformed entries skipped", self.skipped) } else { String::new() }).yellow().right_aligned())
+            .title_bottom(Line::from("type to filter | Up/Down/PgUp/PgDn move | Enter select | Esc close").right_aligned());
+
+        let table = Table::new(rows, widths)
+            .header(header)
+            .block(block)
+            .row_highlight_style(Style::default().fg(Color::Black).bg(Color::Green))
+            .highlight_symbol("> ");
+
+        // The state keeps the selected row in view
+        let mut state = TableState::default().with_selected(if servers.is_empty() { None } else { Some(self.selected) });
+        StatefulWidget::render(table, area, buf, &mut state);
+    }
+}
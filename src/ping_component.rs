@@ -1,36 +1,177 @@
-use crate::http_tester::{HttpLatencyMeasurement};
+use crate::{http_tester::{BufferbloatGrade, HttpLatencyMeasurement}, responsiveness::{ResponsivenessClass, ResponsivenessMeasurement}};
 use ratatui::{style::{Color, Style, Stylize}, text::{Line, Text}, widgets::{Block, Widget}};
 
-#[derive(Default, Clone)]
+#[derive(Clone)]
 pub struct PingComponent {
     ping_measurement: HttpLatencyMeasurement,
+    download_loaded: HttpLatencyMeasurement,
+    upload_loaded: HttpLatencyMeasurement,
+    icmp_measurement: Result<HttpLatencyMeasurement, String>,
+    responsiveness: Result<ResponsivenessMeasurement, String>,
     active: bool,
 }
 
+impl Default for PingComponent {
+    fn default() -> Self {
+        PingComponent {
+            ping_measurement: HttpLatencyMeasurement::default(),
+            download_loaded: HttpLatencyMeasurement::default(),
+            upload_loaded: HttpLatencyMeasurement::default(),
+            icmp_measurement: Ok(HttpLatencyMeasurement::default()),
+            responsiveness: Ok(ResponsivenessMeasurement::default()),
+            active: false,
+        }
+    }
+}
+
 impl PingComponent {
     pub fn set_ping_measurement(&mut self, ping: HttpLatencyMeasurement) {
         self.ping_measurement = ping;
     }
+    pub fn set_download_loaded(&mut self, latency: HttpLatencyMeasurement) {
+        self.download_loaded = latency;
+    }
+    pub fn set_upload_loaded(&mut self, latency: HttpLatencyMeasurement) {
+        self.upload_loaded = latency;
+    }
+    pub fn set_icmp_measurement(&mut self, icmp: Result<HttpLatencyMeasurement, String>) {
+        self.icmp_measurement = icmp;
+    }
+    pub fn set_responsiveness(&mut self, responsiveness: Result<ResponsivenessMeasurement, String>) {
+        self.responsiveness = responsiveness;
+    }
     pub fn set_active(&mut self, active: bool) {
         self.active = active;
     }
 }
 
+impl PingComponent {
+    /// Formats a latency statistic, or a placeholder when no probe got a
+    /// response so a dead link doesn't read as 0 ms.
+    fn format_ms(&self, value: f64) -> String {
+        if self.ping_measurement.total_measurments == 0 {
+            "--".to_string()
+        } else {
+            format!("{:.2}", value)
+        }
+    }
+
+    fn loaded_line(label: &str, latency: &HttpLatencyMeasurement) -> Line<'static> {
+        if latency.total_measurments == 0 {
+            return Line::from(format!("Loaded ({}): --", label)).centered();
+        }
+        Line::from(format!(
+            "Loaded ({}): {:.2} ms | Jitter: {:.2} ms | P90: {:.2} ms",
+            label, latency.avg, latency.jitter, latency.p90
+        ))
+            .centered()
+    }
+
+    fn icmp_line(&self) -> Line<'static> {
+        match &self.icmp_measurement {
+            Ok(icmp) if icmp.total_measurments > 0 => Line::from(format!(
+                "ICMP: {:.2} ms | Jitter: {:.2} ms | Lost: {}/{}",
+                icmp.avg, icmp.jitter, icmp.lost, icmp.sent()
+            ))
+                .bold()
+                .magenta()
+                .centered(),
+            Ok(icmp) if icmp.lost > 0 => Line::from(format!("ICMP: no reply | Lost: {}/{}", icmp.lost, icmp.sent()))
+                .fg(Color::Red)
+                .centered(),
+            Ok(_) => Line::from("ICMP: --").centered(),
+            Err(e) => Line::from(format!("ICMP: unavailable ({})", e)).centered(),
+        }
+    }
+
+    fn responsiveness_line(&self) -> Line<'static> {
+        let responsiveness = match &self.responsiveness {
+            Ok(responsiveness) => responsiveness,
+            Err(e) => return Line::from(format!("Responsiveness: unavailable ({})", e)).centered(),
+        };
+        match responsiveness.class() {
+            Some(class) => {
+                let color = match class {
+                    ResponsivenessClass::High => Color::Green,
+                    ResponsivenessClass::Medium => Color::Yellow,
+                    ResponsivenessClass::Low => Color::Red,
+                };
+                // Without self probes the score is not the draft's RPM, so it says so
+                let text = if responsiveness.foreign_only() {
+                    format!(
+                        "Responsiveness: {:.0} RPM, foreign probes only ({}) | Loaded request: {:.2} ms",
+                        responsiveness.rpm, class, responsiveness.http_foreign
+                    )
+                } else {
+                    format!(
+                        "Responsiveness: {:.0} RPM ({}) | Loaded RTT: {:.2} ms",
+                        responsiveness.rpm, class, responsiveness.http_self.unwrap_or_default()
+                    )
+                };
+                Line::from(text)
+                    .bold()
+                    .fg(color)
+                    .centered()
+            }
+            None => Line::from("Responsiveness: --").bold().centered(),
+        }
+    }
+
+    fn bufferbloat_line(&self) -> Line<'static> {
+        let grade = BufferbloatGrade::from_latencies(&self.ping_measurement, &[&self.download_loaded, &self.upload_loaded]);
+        match grade {
+            Some((grade, increase)) => {
+                let color = match grade {
+                    BufferbloatGrade::APlus | BufferbloatGrade::A => Color::Green,
+                    BufferbloatGrade::B | BufferbloatGrade::C => Color::Yellow,
+                    BufferbloatGrade::D | BufferbloatGrade::F => Color::Red,
+                };
+                Line::from(format!("Bufferbloat: {} (+{:.2} ms)", grade, increase))
+                    .bold()
+                    .fg(color)
+                    .centered()
+            }
+            None => Line::from("Bufferbloat: --").bold().centered(),
+        }
+    }
+}
+
 impl Widget for &PingComponent{
     fn render(self, area: ratatui::layout::Rect, buf: &mut ratatui::buffer::Buffer) {
-         let ping_res = Text::from(vec![
-            Line::from(format!("Average Latency: {:.2} ms", self.ping_measurement.avg))
+        let ping = &self.ping_measurement;
+        let ping_res = Text::from(vec![
+            Line::from(format!("Average Latency: {} ms", self.format_ms(ping.avg)))
                 .bold()
                 .blue()
                 .centered(),
-            Line::from(format!("Min Latency: {:.2} ms", self.ping_measurement.min))
+            Line::from(format!("Min Latency: {} ms", self.format_ms(ping.min)))
                 .bold()
                 .green()
                 .centered(),
-            Line::from(format!("Max Latency: {:.2} ms", self.ping_measurement.max))
+            Line::from(format!("Max Latency: {} ms", self.format_ms(ping.max)))
                 .bold()
                 .red()
                 .centered(),
+            Line::from(format!(
+                "Median: {} ms | P90: {} ms | P99: {} ms",
+                self.format_ms(ping.median), self.format_ms(ping.p90), self.format_ms(ping.p99)
+            ))
+                .centered(),
+            Line::from(format!(
+                "Jitter: {} ms | Std Dev: {} ms",
+                self.format_ms(ping.jitter), self.format_ms(ping.std_dev)
+            ))
+                .bold()
+                .yellow()
+                .centered(),
+            Line::from(format!("Lost: {}/{} ({:.1}%)", ping.lost, ping.sent(), ping.loss_percent()))
+                .fg(if ping.lost > 0 { Color::Red } else { Color::Reset })
+                .centered(),
+            self.icmp_line(),
+            PingComponent::loaded_line("download", &self.download_loaded),
+            PingComponent::loaded_line("upload", &self.upload_loaded),
+            self.bufferbloat_line(),
+            self.responsiveness_line(),
         ]);
 
         let block = Block::bordered()
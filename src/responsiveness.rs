@@ -0,0 +1,312 @@
+use reqwest::{Client, RequestBuilder, Version};
+use std::{fmt, io::Error, time::{Duration, Instant}};
+use tokio::{sync::mpsc, task::JoinSet};
+
+use crate::http_tester::{build_transfer_client, download_body, join_streams, HttpTester, HttpThroughputSample, HttpTimingBreakdown, ThroughputMeter, TransferSettings};
+
+/// Pause between two rounds of probes.
+const PROBE_INTERVAL: Duration = Duration::from_millis(100);
+/// How often the load is checked for saturation.
+const RAMP_INTERVAL: Duration = Duration::from_secs(1);
+/// Throughput growth over one ramp interval below which the link counts as
+/// saturated and no more flows are opened.
+const SATURATION_GAIN: f64 = 0.05;
+const MAX_LOAD_FLOWS: usize = 16;
+/// Share of the fastest probes kept by the trimmed mean.
+const TRIMMED_SHARE: f64 = 0.95;
+
+/// Classification of an RPM score used by Apple's networkQuality.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponsivenessClass {
+    Low,
+    Medium,
+    High,
+}
+
+impl ResponsivenessClass {
+    pub fn from_rpm(rpm: f64) -> Self {
+        match rpm {
+            x if x < 300.0 => ResponsivenessClass::Low,
+            x if x <= 1000.0 => ResponsivenessClass::Medium,
+            _ => ResponsivenessClass::High,
+        }
+    }
+}
+
+impl fmt::Display for ResponsivenessClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResponsivenessClass::Low => write!(f, "Low"),
+            ResponsivenessClass::Medium => write!(f, "Medium"),
+            ResponsivenessClass::High => write!(f, "High"),
+        }
+    }
+}
+
+/// Round trips per minute while the link is saturated, as defined by the
+/// IETF "Responsiveness under Working Conditions" draft. The times are the
+/// trimmed means of the probes, in milliseconds.
+#[derive(Debug, Default, Clone)]
+pub struct ResponsivenessMeasurement {
+    pub rpm: f64,
+    pub tcp_foreign: f64, // TCP handshake on a new connection
+    pub tls_foreign: Option<f64>, // TLS handshake on a new connection, None for plain HTTP
+    pub http_foreign: f64, // request on a new connection
+    pub http_self: Option<f64>, // request on the load-generating connections, None when they are HTTP/1.1
+    pub foreign_probes: usize,
+    pub self_probes: usize,
+    pub lost: usize, // probes that failed or timed out
+    pub flows: usize, // load-generating flows open at the end
+    pub throughput: f64, // bits per second of the load
+}
+
+impl ResponsivenessMeasurement {
+    /// Classification of the score, `None` before anything was measured.
+    pub fn class(&self) -> Option<ResponsivenessClass> {
+        if self.rpm > 0.0 {
+            Some(ResponsivenessClass::from_rpm(self.rpm))
+        } else {
+            None
+        }
+    }
+
+    /// Whether the score comes from the foreign probes alone, as the load
+    /// connections could not carry self probes. It is then not the draft's
+    /// RPM.
+    pub fn foreign_only(&self) -> bool {
+        self.http_self.is_none()
+    }
+}
+
+/// Mean of the fastest `TRIMMED_SHARE` of `samples`.
+fn trimmed_mean(mut samples: Vec<f64>) -> Option<f64> {
+    if samples.is_empty() {
+        return None;
+    }
+    samples.sort_by(|a, b| a.total_cmp(b));
+    let kept = &samples[..((samples.len() as f64 * TRIMMED_SHARE).ceil() as usize).max(1)];
+    Some(kept.iter().sum::<f64>() / kept.len() as f64)
+}
+
+/// Round trips per minute out of the probe times in milliseconds: the
+/// foreign round trips are averaged, then averaged with the self probes
+/// when there are any.
+fn rpm(tcp_foreign: f64, tls_foreign: Option<f64>, http_foreign: f64, http_self: Option<f64>) -> f64 {
+    let foreign = match tls_foreign {
+        Some(tls) => (tcp_foreign + tls + http_foreign) / 3.0,
+        None => (tcp_foreign + http_foreign) / 2.0,
+    };
+    match http_self {
+        Some(own) => 60_000.0 / ((foreign + own) / 2.0),
+        None => 60_000.0 / foreign,
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// Outcome of a self probe.
+enum SelfProbe {
+    Answered(f64),
+    /// The answer came over HTTP/1.1, so the probe had to open a connection
+    /// of its own instead of sharing a load-generating one
+    NotMultiplexed,
+    Lost,
+}
+
+async fn send_self_probe(request: RequestBuilder) -> SelfProbe {
+    let start = Instant::now();
+    match request.send().await {
+        Ok(resp) if resp.status().is_success() && resp.version() == Version::HTTP_2 => SelfProbe::Answered(millis(start.elapsed())),
+        Ok(resp) if resp.status().is_success() => SelfProbe::NotMultiplexed,
+        _ => SelfProbe::Lost,
+    }
+}
+
+/// Saturates the link with downloads built by `load` and probes it until
+/// `settings.test_duration` runs out. The load starts with
+/// `settings.connections` flows and grows by as many every `RAMP_INTERVAL`
+/// while throughput keeps rising; each flow has a client of its own, so its
+/// own connection.
+///
+/// Every `PROBE_INTERVAL` a foreign probe times a fresh connection to
+/// `probe`'s URL and a self probe sends the request built by `self_probe`
+/// through the client of one of the flows. Over HTTP/2, which needs an
+/// `https` URL whose server offers `h2`, it is multiplexed on that flow's
+/// connection. Over HTTP/1.1 it can't be, so self probes stop at the first
+/// such answer and the score is left to the foreign probes. Probes sent
+/// during `settings.warmup` are left out. Load progress is sent to
+/// `progress`.
+pub async fn responsiveness_phase<L, P>(settings: &TransferSettings, probe: HttpTester, progress: mpsc::UnboundedSender<HttpThroughputSample>, load: L, self_probe: P) -> Result<ResponsivenessMeasurement, Error>
+where
+    L: Fn(&Client) -> RequestBuilder + Clone + Send + 'static,
+    P: Fn(&Client) -> RequestBuilder,
+{
+    let meter = ThroughputMeter::start(progress);
+    let counter = meter.counter();
+    let start = Instant::now();
+    let deadline = start + settings.test_duration;
+    let step = settings.connections.max(1);
+    let mut flows = JoinSet::new();
+    let mut clients = Vec::new();
+    let add_flows = |flows: &mut JoinSet<Result<(), Error>>, clients: &mut Vec<Client>, count: usize| {
+        for _ in 0..count {
+            let client = build_transfer_client(settings);
+            clients.push(client.clone());
+            let load = load.clone();
+            let counter = counter.clone();
+            flows.spawn(async move {
+                while Instant::now() < deadline {
+                    download_body(load(&client), deadline, &counter).await?;
+                }
+                Ok(())
+            });
+        }
+    };
+    add_flows(&mut flows, &mut clients, step);
+    let mut flow_count = step;
+
+    let mut probes: JoinSet<(bool, Result<HttpTimingBreakdown, Error>, Option<SelfProbe>)> = JoinSet::new();
+    let mut foreign = Vec::new();
+    let mut self_times = Vec::new();
+    let mut multiplexed = true;
+    let mut lost = 0;
+    let mut ticker = tokio::time::interval(PROBE_INTERVAL);
+    let mut last_ramp = start;
+    let mut last_bytes = 0;
+    let mut last_speed = 0.0;
+    let mut saturated = false;
+    let mut next_flow = 0;
+    loop {
+        ticker.tick().await;
+        while let Some(result) = probes.try_join_next() {
+            let (counted, timing, own) = match result {
+                Ok(result) => result,
+                Err(_) => continue,
+            };
+            if matches!(own, Some(SelfProbe::NotMultiplexed)) {
+                multiplexed = false;
+            }
+            if !counted {
+                continue;
+            }
+            match timing {
+                Ok(timing) => foreign.push(timing),
+                Err(_) => lost += 1,
+            }
+            match own {
+                Some(SelfProbe::Answered(time)) => self_times.push(time),
+                Some(SelfProbe::Lost) => lost += 1,
+                Some(SelfProbe::NotMultiplexed) | None => {}
+            }
+        }
+
+        if Instant::now() >= deadline {
+            break;
+        }
+        if !saturated && last_ramp.elapsed() >= RAMP_INTERVAL {
+            let bytes = counter.get();
+            let speed = (bytes - last_bytes) as f64 / last_ramp.elapsed().as_secs_f64();
+            if speed <= last_speed * (1.0 + SATURATION_GAIN) || flow_count >= MAX_LOAD_FLOWS {
+                saturated = true;
+            } else {
+                let count = step.min(MAX_LOAD_FLOWS - flow_count);
+                add_flows(&mut flows, &mut clients, count);
+                flow_count += count;
+            }
+            last_ramp = Instant::now();
+            last_bytes = bytes;
+            last_speed = speed;
+        }
+        let probe = probe.clone();
+        // The flows take turns carrying the self probes
+        let own = if multiplexed {
+            next_flow = (next_flow + 1) % clients.len();
+            Some(self_probe(&clients[next_flow]))
+        } else {
+            None
+        };
+        let counted = start.elapsed() >= settings.warmup;
+        probes.spawn(async move {
+            let own = async {
+                match own {
+                    Some(request) => Some(send_self_probe(request).await),
+                    None => None,
+                }
+            };
+            let (timing, own) = tokio::join!(probe.measure_timing(), own);
+            (counted, timing, own)
+        });
+    }
+    // Probes still in flight at the deadline are neither counted nor lost
+    probes.shutdown().await;
+
+    let error = join_streams(flows).await;
+    let (bytes, _) = meter.finish().await;
+    let tcp_foreign = trimmed_mean(foreign.iter().map(|timing| millis(timing.connect)).collect());
+    let tls: Vec<f64> = foreign.iter().filter_map(|timing| timing.tls.map(millis)).collect();
+    let tls_foreign = trimmed_mean(tls);
+    let http_foreign = trimmed_mean(foreign.iter().map(|timing| millis(timing.ttfb)).collect());
+    let (tcp_foreign, http_foreign) = match (tcp_foreign, http_foreign) {
+        (Some(tcp), Some(http)) => (tcp, http),
+        _ => return Err(error.unwrap_or_else(|| Error::other("No responsiveness probe got a response"))),
+    };
+    let self_probes = self_times.len();
+    // Self probes answered before one came back over HTTP/1.1 don't make
+    // the score a full one
+    let http_self = if multiplexed { trimmed_mean(self_times) } else { None };
+
+    Ok(ResponsivenessMeasurement {
+        rpm: rpm(tcp_foreign, tls_foreign, http_foreign, http_self),
+        tcp_foreign,
+        tls_foreign,
+        http_foreign,
+        http_self,
+        foreign_probes: foreign.len(),
+        self_probes,
+        lost,
+        flows: flow_count,
+        throughput: (bytes * 8) as f64 / start.elapsed().as_secs_f64(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trimmed_mean_drops_the_slowest_probes() {
+        assert_eq!(trimmed_mean(Vec::new()), None);
+        assert_eq!(trimmed_mean(vec![7.0]), Some(7.0));
+        // 95% of 20 probes keeps the 19 fastest
+        let mut samples: Vec<f64> = (1..=19).map(|_| 10.0).collect();
+        samples.push(1000.0);
+        assert_eq!(trimmed_mean(samples), Some(10.0));
+        // Fewer than 20 probes round the kept share up, so nothing is dropped
+        assert_eq!(trimmed_mean(vec![30.0, 10.0, 20.0]), Some(20.0));
+    }
+
+    #[test]
+    fn rpm_averages_foreign_and_self_probes() {
+        // Foreign round trip (10 + 20 + 30) / 3 = 20 ms, with 40 ms self
+        // probes 30 ms on average
+        assert_eq!(rpm(10.0, Some(20.0), 30.0, Some(40.0)), 2000.0);
+        // Without TLS the foreign round trip is (10 + 30) / 2 = 20 ms
+        assert_eq!(rpm(10.0, None, 30.0, Some(40.0)), 2000.0);
+    }
+
+    #[test]
+    fn rpm_without_self_probes() {
+        assert_eq!(rpm(10.0, Some(20.0), 30.0, None), 3000.0);
+        assert_eq!(rpm(50.0, None, 150.0, None), 600.0);
+    }
+
+    #[test]
+    fn foreign_only_without_self_probes() {
+        let measurement = ResponsivenessMeasurement { rpm: 600.0, ..Default::default() };
+        assert!(measurement.foreign_only());
+        assert_eq!(measurement.class(), Some(ResponsivenessClass::Medium));
+        assert!(!ResponsivenessMeasurement { http_self: Some(5.0), ..measurement }.foreign_only());
+    }
+}
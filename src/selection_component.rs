@@ -0,0 +1,56 @@
+use ratatui::{layout::Constraint, style::{Color, Style, Stylize}, text::Line, widgets::{Block, Row, Table, Widget}};
+
+use crate::server_selection::Candidate;
+
+#[derive(Default, Clone)]
+pub struct SelectionComponent {
+    candidates: Vec<Candidate>,
+}
+
+impl SelectionComponent {
+    pub fn set_candidates(&mut self, candidates: Vec<Candidate>) {
+        self.candidates = candidates;
+    }
+
+    fn row(candidate: &Candidate) -> Row<'static> {
+        let server = &candidate.server;
+        let distance = server.distance.map_or("-".to_string(), |distance| format!("{:.0} km", distance));
+        let (latency, lost) = match &candidate.latency {
+            None if candidate.timed_out => ("timed out".to_string(), String::new()),
+            None => ("probing...".to_string(), String::new()),
+            Some(latency) if latency.total_measurments == 0 => ("no reply".to_string(), format!("{}/{}", latency.lost, latency.sent())),
+            Some(latency) => (format!("{:.2} ms", latency.median), format!("{}/{}", latency.lost, latency.sent())),
+        };
+        let row = Row::new(vec![server.label(), server.host.clone(), distance, latency, lost]);
+        match &candidate.latency {
+            None if candidate.timed_out => row.fg(Color::Red),
+            None => row.fg(Color::DarkGray),
+            Some(latency) if latency.total_measurments == 0 => row.fg(Color::Red),
+            Some(_) => row,
+        }
+    }
+}
+
+impl Widget for &SelectionComponent {
+    fn render(self, area: ratatui::layout::Rect, buf: &mut ratatui::buffer::Buffer) {
+        let header = Row::new(vec!["Server", "Host", "Distance", "Median", "Lost"]).bold();
+        let rows: Vec<Row> = self.candidates.iter().map(SelectionComponent::row).collect();
+        let widths = [
+            Constraint::Min(24),
+            Constraint::Min(16),
+            Constraint::Length(10),
+            Constraint::Length(12),
+            Constraint::Length(6),
+        ];
+
+        let block = Block::bordered()
+            .border_style(Style::default().fg(Color::Green))
+            .title(Line::from("Selecting the best server").bold());
+
+        let table = Table::new(rows, widths)
+            .header(header)
+            .block(block);
+
+        Widget::render(table, area, buf);
+    }
+}
@@ -0,0 +1,408 @@
+use bytes::Bytes;
+use std::{collections::HashMap, io::Error, net::SocketAddr, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+use tokio::{io::{self, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader}, net::{TcpListener, TcpStream, UdpSocket}};
+
+use crate::{cli::ServeArgs, http_tester::random_payload, udp_tester::{UDP_HEADER_SIZE, UDP_MAGIC}};
+
+/// Data the download endpoints repeat until the requested size is sent.
+const FILL_SIZE: usize = 1024 * 1024;
+const MAX_LINE_SIZE: u64 = 8 * 1024;
+const MAX_HEADERS: usize = 100;
+/// Largest `random{N}x{N}.jpg` served, about 200 MB.
+const MAX_IMAGE_SIDE: usize = 10_000;
+const LATENCY_BODY: &[u8] = b"test=test\n";
+const TCP_GREETING: &str = "HELLO 2.11 (2.11.0) speedtest-tui";
+/// UDP peers silent for this long are forgotten by the echo responder.
+const UDP_PEER_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Serves the endpoints the HTTP and TCP backends test against until
+/// Ctrl-C, so another machine can run a test against this one:
+///
+/// - `GET /speedtest/random{N}x{N}.jpg` replies with about `N * N * 2` random bytes
+/// - `POST /speedtest/upload.php` discards the body and replies with `size=<bytes>`
+/// - `/` and `/speedtest/latency.txt` reply with a short body for latency probes
+/// - a connection opening with `HI` speaks the speedtest.net TCP protocol instead
+/// - UDP packets to the same port are echoed back for the UDP test
+pub async fn run(args: &ServeArgs) -> Result<(), Error> {
+    let fill = random_payload(FILL_SIZE);
+
+    let listener = TcpListener::bind(args.bind).await?;
+    println!("Serving HTTP and TCP protocol tests on {}", listener.local_addr()?);
+    tokio::spawn(accept_loop(listener, fill.clone(), false));
+
+    let socket = UdpSocket::bind(args.bind).await?;
+    println!("Serving UDP echo on {}", socket.local_addr()?);
+    tokio::spawn(udp_echo(socket));
+
+    if let Some(port) = args.tcp_port {
+        let listener = TcpListener::bind(SocketAddr::new(args.bind.ip(), port)).await?;
+        println!("Serving TCP protocol tests on {}", listener.local_addr()?);
+        tokio::spawn(accept_loop(listener, fill.clone(), true));
+    }
+
+    tokio::signal::ctrl_c().await
+}
+
+async fn accept_loop(listener: TcpListener, fill: Bytes, tcp_only: bool) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let fill = fill.clone();
+                tokio::spawn(async move {
+                    // Clients drop their connections mid transfer when a phase ends
+                    let _ = handle_connection(stream, fill, tcp_only).await;
+                });
+            }
+            Err(e) => eprintln!("Failed to accept connection: {}", e),
+        }
+    }
+}
+
+/// Echoes the packets of the UDP test, stamping each one with when it
+/// arrived and how many packets its sender got through so far.
+async fn udp_echo(socket: UdpSocket) {
+    let start = Instant::now();
+    let mut peers: HashMap<SocketAddr, (u32, Instant)> = HashMap::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let (len, peer) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(_) => continue,
+        };
+        if len < UDP_HEADER_SIZE || &buf[..4] != UDP_MAGIC {
+            continue;
+        }
+        let now = Instant::now();
+        peers.retain(|_, (_, last)| now.duration_since(*last) < UDP_PEER_TIMEOUT);
+        let (count, last) = peers.entry(peer).or_insert((0, now));
+        *count += 1;
+        *last = now;
+        buf[16..24].copy_from_slice(&(start.elapsed().as_micros() as u64).to_be_bytes());
+        buf[24..28].copy_from_slice(&count.to_be_bytes());
+        let _ = socket.send_to(&buf[..len], peer).await;
+    }
+}
+
+/// Both protocols start with a line, which tells them apart.
+async fn handle_connection(stream: TcpStream, fill: Bytes, tcp_only: bool) -> Result<(), Error> {
+    stream.set_nodelay(true)?;
+    let mut stream = BufReader::new(stream);
+    let first_line = match read_line(&mut stream).await? {
+        Some(line) => line,
+        None => return Ok(()),
+    };
+    if tcp_only || first_line.starts_with("HI") {
+        serve_tcp(&mut stream, first_line, &fill).await
+    } else {
+        serve_http(&mut stream, first_line, &fill).await
+    }
+}
+
+/// Reads one line without its line ending, `None` once the peer closed
+/// the connection.
+async fn read_line(stream: &mut BufReader<TcpStream>) -> Result<Option<String>, Error> {
+    let mut line = String::new();
+    let read = (&mut *stream).take(MAX_LINE_SIZE).read_line(&mut line).await?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with('\n') && read as u64 == MAX_LINE_SIZE {
+        return Err(Error::other("Line too long"));
+    }
+    Ok(Some(line.trim_end_matches(['\r', '\n']).to_string()))
+}
+
+/// Writes `size` bytes of `fill`, repeating it as needed.
+async fn write_fill(stream: &mut BufReader<TcpStream>, fill: &Bytes, size: usize) -> Result<(), Error> {
+    let mut remaining = size;
+    while remaining > 0 {
+        let piece = remaining.min(fill.len());
+        stream.get_mut().write_all(&fill[..piece]).await?;
+        remaining -= piece;
+    }
+    Ok(())
+}
+
+async fn discard(stream: &mut BufReader<TcpStream>, size: u64) -> Result<u64, Error> {
+    let read = io::copy(&mut (&mut *stream).take(size), &mut io::sink()).await?;
+    if read < size {
+        return Err(Error::other("Connection closed mid body"));
+    }
+    Ok(read)
+}
+
+async fn serve_tcp(stream: &mut BufReader<TcpStream>, first_line: String, fill: &Bytes) -> Result<(), Error> {
+    let mut line = first_line;
+    loop {
+        let mut parts = line.split_whitespace();
+        match (parts.next(), parts.next().and_then(|size| size.parse::<usize>().ok())) {
+            (Some("HI"), _) => {
+                stream.get_mut().write_all(format!("{}\n", TCP_GREETING).as_bytes()).await?;
+            }
+            (Some("PING"), _) => {
+                let timestamp = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis();
+                stream.get_mut().write_all(format!("PONG {}\n", timestamp).as_bytes()).await?;
+            }
+            (Some("DOWNLOAD"), Some(size)) => {
+                // `size` counts the `DOWNLOAD ` prefix and the final newline
+                let prefix = &b"DOWNLOAD "[..size.min(9)];
+                stream.get_mut().write_all(prefix).await?;
+                write_fill(stream, fill, size.saturating_sub(prefix.len() + 1)).await?;
+                if size > prefix.len() {
+                    stream.get_mut().write_all(b"\n").await?;
+                }
+            }
+            (Some("UPLOAD"), Some(size)) => {
+                // `size` counts the command line, which is already read
+                let start = Instant::now();
+                discard(stream, size.saturating_sub(line.len() + 1) as u64).await?;
+                let reply = format!("OK {} {}\n", size, start.elapsed().as_millis());
+                stream.get_mut().write_all(reply.as_bytes()).await?;
+            }
+            (Some("QUIT"), _) => return Ok(()),
+            _ => {
+                stream.get_mut().write_all(b"ERROR\n").await?;
+            }
+        }
+        line = match read_line(stream).await? {
+            Some(line) => line,
+            None => return Ok(()),
+        };
+    }
+}
+
+/// What the headers of a request say about its body and connection.
+#[derive(Debug, Default)]
+struct HttpRequestHead {
+    content_length: u64,
+    chunked: bool,
+    close: bool,
+}
+
+async fn read_request_head(stream: &mut BufReader<TcpStream>, http_10: bool) -> Result<HttpRequestHead, Error> {
+    let mut head = HttpRequestHead { close: http_10, ..Default::default() };
+    for _ in 0..MAX_HEADERS {
+        let line = match read_line(stream).await? {
+            Some(line) => line,
+            None => return Err(Error::other("Connection closed mid headers")),
+        };
+        if line.is_empty() {
+            return Ok(head);
+        }
+        let (name, value) = match line.split_once(':') {
+            Some((name, value)) => (name.trim().to_ascii_lowercase(), value.trim().to_ascii_lowercase()),
+            None => return Err(Error::other(format!("Malformed header: {}", line))),
+        };
+        match name.as_str() {
+            "content-length" => {
+                head.content_length = value.parse().map_err(|_| Error::other("Invalid Content-Length"))?;
+            }
+            "transfer-encoding" => head.chunked = value.contains("chunked"),
+            "connection" => head.close = value == "close" || (http_10 && value != "keep-alive"),
+            _ => {}
+        }
+    }
+    Err(Error::other("Too many headers"))
+}
+
+/// Reads and drops the request body, returning its size.
+async fn read_request_body(stream: &mut BufReader<TcpStream>, head: &HttpRequestHead) -> Result<u64, Error> {
+    if !head.chunked {
+        return discard(stream, head.content_length).await;
+    }
+    let mut total = 0;
+    loop {
+        let line = match read_line(stream).await? {
+            Some(line) => line,
+            None => return Err(Error::other("Connection closed mid body")),
+        };
+        let size_field = line.split(';').next().unwrap_or_default().trim();
+        let size = u64::from_str_radix(size_field, 16).map_err(|_| Error::other("Invalid chunk size"))?;
+        if size == 0 {
+            // Trailers, up to the empty line that ends the body
+            while let Some(line) = read_line(stream).await? {
+                if line.is_empty() {
+                    break;
+                }
+            }
+            return Ok(total);
+        }
+        total += discard(stream, size).await?;
+        read_line(stream).await?;
+    }
+}
+
+/// Side of a `/speedtest/random{N}x{N}.jpg` path, if it is one.
+fn image_side(path: &str) -> Option<usize> {
+    let dimensions = path.strip_prefix("/speedtest/random")?.strip_suffix(".jpg")?;
+    let (width, height) = dimensions.split_once('x')?;
+    let side: usize = width.parse().ok()?;
+    if height.parse::<usize>().ok()? != side || side > MAX_IMAGE_SIDE {
+        return None;
+    }
+    Some(side)
+}
+
+async fn write_response_head(stream: &mut BufReader<TcpStream>, status: &str, content_type: &str, length: usize, close: bool) -> Result<(), Error> {
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: {}\r\n\r\n",
+        status, content_type, length, if close { "close" } else { "keep-alive" }
+    );
+    stream.get_mut().write_all(head.as_bytes()).await
+}
+
+async fn serve_http(stream: &mut BufReader<TcpStream>, first_line: String, fill: &Bytes) -> Result<(), Error> {
+    let mut request_line = first_line;
+    loop {
+        let mut parts = request_line.split_whitespace();
+        let (method, target, version) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(method), Some(target), Some(version)) => (method.to_string(), target.to_string(), version.to_string()),
+            _ => return Err(Error::other(format!("Malformed request line: {}", request_line))),
+        };
+        let head = read_request_head(stream, version == "HTTP/1.0").await?;
+        let body_size = read_request_body(stream, &head).await?;
+
+        let path = target.split('?').next().unwrap_or_default();
+        let head_only = method == "HEAD";
+        match (method.as_str(), path, image_side(path)) {
+            ("GET" | "HEAD", "/" | "/speedtest/latency.txt", _) => {
+                write_response_head(stream, "200 OK", "text/plain", LATENCY_BODY.len(), head.close).await?;
+                if !head_only {
+                    stream.get_mut().write_all(LATENCY_BODY).await?;
+                }
+            }
+            ("POST", "/speedtest/upload.php", _) => {
+                let body = format!("size={}", body_size);
+                write_response_head(stream, "200 OK", "text/plain", body.len(), head.close).await?;
+                stream.get_mut().write_all(body.as_bytes()).await?;
+            }
+            ("GET" | "HEAD", _, Some(side)) => {
+                let size = side * side * 2;
+                write_response_head(stream, "200 OK", "image/jpeg", size, head.close).await?;
+                if !head_only {
+                    write_fill(stream, fill, size).await?;
+                }
+            }
+            _ => {
+                write_response_head(stream, "404 Not Found", "text/plain", 0, head.close).await?;
+            }
+        }
+        stream.get_mut().flush().await?;
+
+        if head.close {
+            return Ok(());
+        }
+        request_line = match read_line(stream).await? {
+            Some(line) => line,
+            None => return Ok(()),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    use crate::{http_tester::{HttpTester, TransferSettings}, tcp_tester::TcpTester, udp_tester::UdpTester};
+
+    /// Starts the HTTP, TCP and UDP endpoints on the same ephemeral port.
+    async fn start() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(accept_loop(listener, random_payload(FILL_SIZE), false));
+        tokio::spawn(udp_echo(UdpSocket::bind(address).await.unwrap()));
+        address
+    }
+
+    fn settings() -> TransferSettings {
+        TransferSettings {
+            connections: 2,
+            test_duration: Duration::from_millis(1500),
+            warmup: Duration::ZERO,
+            ..Default::default()
+        }
+    }
+
+    /// Server side of a connection the client already wrote `request` to.
+    async fn received(request: &[u8]) -> BufReader<TcpStream> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        client.write_all(request).await.unwrap();
+        client.shutdown().await.unwrap();
+        BufReader::new(listener.accept().await.unwrap().0)
+    }
+
+    #[tokio::test]
+    async fn http_tester() {
+        let address = start().await;
+        let mut tester = HttpTester::new(format!("http://{}", address).as_str());
+        tester.set_settings(settings());
+        assert!(tester.measure_latency().await.unwrap() > 0.0);
+        let download = tester.measure_download(mpsc::unbounded_channel().0).await.unwrap();
+        assert!(download.bits > 0);
+        let upload = tester.measure_upload(mpsc::unbounded_channel().0).await.unwrap();
+        assert!(upload.bits > 0);
+    }
+
+    #[tokio::test]
+    async fn tcp_tester() {
+        let address = start().await;
+        let tester = TcpTester::new(address.to_string().as_str(), settings());
+        assert!(tester.measure_latency().await.unwrap() > 0.0);
+        let download = tester.measure_download(mpsc::unbounded_channel().0).await.unwrap();
+        assert!(download.bits > 0);
+        let upload = tester.measure_upload(mpsc::unbounded_channel().0).await.unwrap();
+        assert!(upload.bits > 0);
+    }
+
+    #[tokio::test]
+    async fn udp_tester() {
+        let address = start().await;
+        let mut tester = UdpTester::new(address.to_string().as_str());
+        tester.packet_count = 20;
+        tester.packet_interval = Duration::from_millis(5);
+        let udp = tester.measure().await.unwrap();
+        assert_eq!(udp.sent, 20);
+        assert_eq!(udp.received, 20);
+        assert_eq!(udp.duplicates, 0);
+    }
+
+    #[test]
+    fn image_sides() {
+        assert_eq!(image_side("/speedtest/random350x350.jpg"), Some(350));
+        assert_eq!(image_side(&format!("/speedtest/random{0}x{0}.jpg", MAX_IMAGE_SIDE)), Some(MAX_IMAGE_SIDE));
+        assert_eq!(image_side("/speedtest/random350x500.jpg"), None);
+        assert_eq!(image_side(&format!("/speedtest/random{0}x{0}.jpg", MAX_IMAGE_SIDE + 1)), None);
+        assert_eq!(image_side("/speedtest/randomaxa.jpg"), None);
+        assert_eq!(image_side("/speedtest/random350x350.png"), None);
+    }
+
+    #[tokio::test]
+    async fn reads_chunked_body() {
+        let head = HttpRequestHead { chunked: true, ..Default::default() };
+        let mut stream = received(b"5\r\nhello\r\na;ext=1\r\n0123456789\r\n0\r\nTrailer: x\r\n\r\nnext").await;
+        assert_eq!(read_request_body(&mut stream, &head).await.unwrap(), 15);
+        // The next request starts right after the body
+        let mut rest = String::new();
+        stream.read_to_string(&mut rest).await.unwrap();
+        assert_eq!(rest, "next");
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_chunk_size() {
+        let head = HttpRequestHead { chunked: true, ..Default::default() };
+        let mut stream = received(b"zz\r\nhello\r\n0\r\n\r\n").await;
+        assert!(read_request_body(&mut stream, &head).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn chunked_body_cut_short() {
+        let head = HttpRequestHead { chunked: true, ..Default::default() };
+        let mut stream = received(b"5\r\nhello\r\n").await;
+        assert!(read_request_body(&mut stream, &head).await.is_err());
+    }
+}
@@ -0,0 +1,134 @@
+use std::time::{Duration, Instant};
+use tokio::{sync::mpsc, task::{JoinHandle, JoinSet}};
+
+use crate::{http_tester::{latency_series, HttpLatencyMeasurement, HttpTester}, servers::Server};
+
+/// Latency probes sent to each candidate.
+const SELECTION_PINGS: usize = 3;
+/// Longest the selection waits for the candidates, so a server that never
+/// answers does not hold up the test.
+const SELECTION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A server taking part in the selection and its latency, `None` while it
+/// is still being probed or when it timed out.
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub server: Server,
+    pub latency: Option<HttpLatencyMeasurement>,
+    pub timed_out: bool, // no latency before the selection deadline
+}
+
+/// Picks the server to test against by probing a few candidates at once
+/// and keeping the one with the lowest median latency, since the nearest
+/// server is not always the fastest to reach.
+pub struct ServerSelection {
+    pub candidates: Vec<Candidate>,
+    results: mpsc::UnboundedReceiver<(usize, HttpLatencyMeasurement)>,
+    task: JoinHandle<()>,
+    deadline: Instant,
+}
+
+impl Drop for ServerSelection {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl ServerSelection {
+    /// Starts probing `servers` in the background, all of them concurrently,
+    /// for up to `SELECTION_TIMEOUT`.
+    pub fn start(servers: &[Server]) -> Self {
+        let (tx, results) = mpsc::unbounded_channel();
+        let hosts: Vec<String> = servers.iter().map(|server| server.host.clone()).collect();
+        let task = tokio::spawn(async move {
+            let mut probes = JoinSet::new();
+            for (index, host) in hosts.into_iter().enumerate() {
+                let tx = tx.clone();
+                probes.spawn(async move {
+                    let tester = HttpTester::new(format!("http://{}", host).as_str());
+                    let latency = latency_series(SELECTION_PINGS, || tester.measure_latency()).await;
+                    let _ = tx.send((index, latency));
+                });
+            }
+            while probes.join_next().await.is_some() {}
+        });
+        let candidates = servers.iter()
+            .map(|server| Candidate { server: server.clone(), latency: None, timed_out: false })
+            .collect();
+        ServerSelection { candidates, results, task, deadline: Instant::now() + SELECTION_TIMEOUT }
+    }
+
+    /// Takes in the latencies measured since the last call. Past the
+    /// deadline the probing stops and the candidates still waiting for a
+    /// latency are marked as timed out.
+    pub fn update(&mut self) {
+        while let Ok((index, latency)) = self.results.try_recv() {
+            if let Some(candidate) = self.candidates.get_mut(index) {
+                candidate.latency = Some(latency);
+            }
+        }
+        if Instant::now() >= self.deadline {
+            self.task.abort();
+            for candidate in self.candidates.iter_mut().filter(|candidate| candidate.latency.is_none()) {
+                candidate.timed_out = true;
+            }
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.candidates.iter().all(|candidate| candidate.latency.is_some() || candidate.timed_out)
+    }
+
+    /// The candidate with the lowest median latency, `None` when none of
+    /// them answered.
+    pub fn best(&self) -> Option<&Server> {
+        self.candidates.iter()
+            .filter_map(|candidate| candidate.latency.as_ref().map(|latency| (candidate, latency)))
+            .filter(|(_, latency)| latency.total_measurments > 0)
+            .min_by(|(_, a), (_, b)| a.median.total_cmp(&b.median))
+            .map(|(candidate, _)| &candidate.server)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpListener};
+
+    /// Host answering every connection with an empty 200 response.
+    async fn answering() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 1024];
+                    let _ = stream.read(&mut buf).await;
+                    let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").await;
+                });
+            }
+        });
+        address
+    }
+
+    #[tokio::test]
+    async fn silent_candidate_times_out() {
+        // Connections complete in the backlog but never get a response
+        let silent = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let servers = [
+            Server::from_host(silent.local_addr().unwrap().to_string().as_str()),
+            Server::from_host(answering().await.as_str()),
+        ];
+        let start = Instant::now();
+        let mut selection = ServerSelection::start(&servers);
+        while !selection.is_finished() {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            selection.update();
+        }
+        assert!(start.elapsed() < SELECTION_TIMEOUT + Duration::from_secs(1));
+        assert!(selection.candidates[0].timed_out);
+        assert!(!selection.candidates[1].timed_out);
+        assert_eq!(selection.best().map(|server| server.host.as_str()), Some(servers[1].host.as_str()));
+    }
+}
@@ -1,5 +1,8 @@
-use quick_xml::events::{self, Event};
-use reqwest::{self, Response, Result};
+use quick_xml::events::{BytesStart, Event};
+use reqwest::StatusCode;
+use std::{error, fmt, time::Duration};
+
+use crate::http_tester::build_client;
 
 const SERVERS_URLS: [&str; 4] = [
     "http://www.speedtest.net/speedtest-servers-static.php",
@@ -7,26 +10,137 @@ const SERVERS_URLS: [&str; 4] = [
     "http://www.speedtest.net/speedtest-servers.php",
     "http://c.speedtest.net/speedtest-servers.php"
 ];
+const CONFIG_URL: &str = "http://www.speedtest.net/speedtest-config.php";
+/// The client config is only a hint, so it is not waited on for long.
+const CONFIG_TIMEOUT: Duration = Duration::from_secs(3);
+/// Per list URL, so an unreachable speedtest.net is reported soon.
+const LIST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Mean radius of the Earth in km.
+const EARTH_RADIUS: f64 = 6371.0;
 
-#[derive(Default, Clone)]
+/// The client as `speedtest-config.php` sees it.
+#[derive(Debug, Default, Clone)]
+pub struct ClientConfig {
+    pub ip: String,
+    pub isp: String,
+    pub country: String,
+    pub lat: f64,
+    pub lon: f64,
+}
+
+impl ClientConfig {
+    /// Parses the `<client>` element of the config, `None` when it is
+    /// missing or has no coordinates.
+    fn parse(xml: &str) -> Option<Self> {
+        let mut reader = quick_xml::Reader::from_str(xml);
+        reader.config_mut().trim_text(true);
+        loop {
+            match reader.read_event() {
+                Ok(Event::Empty(ref e) | Event::Start(ref e)) if e.name().as_ref() == b"client" => {
+                    let mut client = ClientConfig::default();
+                    let (mut lat, mut lon) = (None, None);
+                    for attribute in e.attributes().flatten() {
+                        let value = String::from_utf8_lossy(&attribute.value).into_owned();
+                        match attribute.key.as_ref() {
+                            b"ip" => client.ip = value,
+                            b"isp" => client.isp = value,
+                            b"country" => client.country = value,
+                            b"lat" => lat = value.parse().ok(),
+                            b"lon" => lon = value.parse().ok(),
+                            _ => {}
+                        }
+                    }
+                    client.lat = lat?;
+                    client.lon = lon?;
+                    return Some(client);
+                }
+                Ok(Event::Eof) | Err(_) => return None,
+                _ => {}
+            }
+        }
+    }
+
+    /// IP and ISP, e.g. `203.0.113.7 - Example ISP`.
+    pub fn describe(&self) -> String {
+        format!("{} - {}", self.ip, self.isp)
+    }
+}
+
+/// An entry of the speedtest.net server list, with every attribute the
+/// list provides.
+#[derive(Debug, Default, Clone)]
 pub struct Server {
-    id: i32,
+    pub id: u32,
     pub url: String,
-    pub name: String,
+    pub url2: Option<String>, // mirror of `url` some servers list
+    pub lat: f64,
+    pub lon: f64,
+    pub name: String, // city
     pub country: String,
+    pub cc: String, // ISO 3166 country code
     pub sponsor: String,
-    pub host: String,
+    pub host: String, // host:port
+    pub https_functional: bool,
+    pub force_ping_select: bool,
+    pub distance: Option<f64>, // km from the client, when its location is known
 }
 
+impl Server {
+    /// A server that is not on the speedtest.net list, known only by its
+    /// `host:port`.
+    pub fn from_host(host: &str) -> Self {
+        Server {
+            name: host.to_string(),
+            host: host.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Whether the server came from the speedtest.net list rather than
+    /// `from_host`.
+    pub fn is_listed(&self) -> bool {
+        self.id != 0
+    }
+
+    /// Great-circle distance in km from `lat`, `lon`, by the haversine
+    /// formula.
+    pub fn distance_to(&self, lat: f64, lon: f64) -> f64 {
+        let (lat1, lat2) = (lat.to_radians(), self.lat.to_radians());
+        let d_lat = lat2 - lat1;
+        let d_lon = (self.lon - lon).to_radians();
+        let a = (d_lat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (d_lon / 2.0).sin().powi(2);
+        2.0 * EARTH_RADIUS * a.sqrt().asin()
+    }
+
+    /// Sponsor, city and country code, e.g. `Example ISP (Buenos Aires, AR)`,
+    /// or just the host for unlisted servers.
+    pub fn label(&self) -> String {
+        if !self.is_listed() {
+            return self.host.clone();
+        }
+        format!("{} ({}, {})", self.sponsor, self.name, self.cc)
+    }
+
+    /// Id, coordinates and distance of a listed server.
+    pub fn location(&self) -> String {
+        let mut location = format!("#{} at {:.4}, {:.4}", self.id, self.lat, self.lon);
+        if let Some(distance) = self.distance {
+            location = format!("{} - {:.0} km away", location, distance);
+        }
+        location
+    }
+}
 
 #[derive(Default, Clone)]
 pub struct Servers {
     servers: Vec<Server>,
+    skipped: usize,
+    truncated: Option<String>, // why the last list ended early
 }
 
 impl Servers {
     pub fn new() -> Self {
-        Self { servers: Vec::new() }
+        Self { servers: Vec::new(), skipped: 0, truncated: None }
     }
 
     fn add_server(&mut self, server: Server) {
@@ -37,72 +151,263 @@ impl Servers {
         &self.servers
     }
 
-    pub async fn update_servers(&mut self) -> Result<()> {
-        let mut response_text: String = String::new();
-        let mut last_error: Option<reqwest::Error> = None;
-        
+    /// Fetches where speedtest.net places the client.
+    pub async fn fetch_client() -> Result<Option<ClientConfig>, ServersError> {
+        let client = build_client(CONFIG_TIMEOUT);
+        let response = client.get(CONFIG_URL).send().await.map_err(ServersError::Network)?;
+        if !response.status().is_success() {
+            return Err(ServersError::HttpStatus(response.status()));
+        }
+        let text = response.text().await.map_err(ServersError::Network)?;
+        Ok(ClientConfig::parse(text.as_str()))
+    }
+
+    /// Fills in the distance of every server from `lat`, `lon` and sorts
+    /// the list nearest first.
+    pub fn sort_by_distance(&mut self, lat: f64, lon: f64) {
+        for server in self.servers.iter_mut() {
+            server.distance = Some(server.distance_to(lat, lon));
+        }
+        self.servers.sort_by(|a, b| a.distance.unwrap_or_default().total_cmp(&b.distance.unwrap_or_default()));
+    }
+
+    /// Entries of the last fetched list that were skipped as malformed.
+    pub fn get_skipped(&self) -> usize {
+        self.skipped
+    }
+
+    /// Why the last fetched list ended before its closing tags, in which
+    /// case the servers after that point are missing.
+    pub fn get_truncated(&self) -> Option<&str> {
+        self.truncated.as_deref()
+    }
+
+    /// Body of the first list URL that answers successfully.
+    async fn fetch_list() -> Result<String, ServersError> {
+        let client = build_client(LIST_TIMEOUT);
+        let mut last_error = None;
         for url in SERVERS_URLS {
-            match reqwest::get(url).await {
-                Ok(response) => {
-                    match response.text().await {
-                        Ok(text) => {
-                            response_text = text;
-                            break;
-                        }
-                        Err(e) => {
-                            last_error = Some(e);
-                            continue;
-                        }
-                    }
-                }
+            let response = match client.get(url).send().await {
+                Ok(response) => response,
                 Err(e) => {
-                    last_error = Some(e);
+                    last_error = Some(ServersError::Network(e));
                     continue;
                 }
+            };
+            if !response.status().is_success() {
+                last_error = Some(ServersError::HttpStatus(response.status()));
+                continue;
+            }
+            match response.text().await {
+                Ok(text) => return Ok(text),
+                Err(e) => last_error = Some(ServersError::Network(e)),
             }
-        } 
+        }
+        Err(last_error.unwrap_or(ServersError::Empty))
+    }
 
-        if response_text.is_empty() {
-            return match last_error {
-                Some(error) => Err(error),
-                None => panic!("No servers responded and no errors were captured"),
-            };
+    /// Builds a server out of the attributes of a `<server>` element. The
+    /// id and host are required, coordinates must be numbers when present.
+    fn parse_server(element: &BytesStart) -> Result<Server, ServersError> {
+        let mut new_server: Server = Server::default();
+        for attribute in element.attributes() {
+            let attribute = attribute.map_err(|e| ServersError::Attribute(e.to_string()))?;
+            let key = String::from_utf8_lossy(attribute.key.as_ref()).into_owned();
+            let value = attribute.unescape_value()
+                .map_err(|e| ServersError::Attribute(format!("{}: {}", key, e)))?
+                .into_owned();
+            let number = |value: &str| value.parse::<f64>().map_err(|e| ServersError::Attribute(format!("{} {:?}: {}", key, value, e)));
+            match key.as_str() {
+                "id" => new_server.id = value.parse().map_err(|e| ServersError::Attribute(format!("id {:?}: {}", value, e)))?,
+                "name" => new_server.name = value,
+                "url" => new_server.url = value,
+                "url2" => new_server.url2 = Some(value),
+                "lat" => new_server.lat = number(value.as_str())?,
+                "lon" => new_server.lon = number(value.as_str())?,
+                "country" => new_server.country = value,
+                "cc" => new_server.cc = value,
+                "sponsor" => new_server.sponsor = value,
+                "host" => new_server.host = value,
+                "https_functional" => new_server.https_functional = value == "1",
+                "force_ping_select" => new_server.force_ping_select = value == "1",
+                _ => {}
+            }
+        }
+        if new_server.id == 0 || new_server.host.is_empty() {
+            return Err(ServersError::Attribute("server without id or host".to_string()));
         }
+        Ok(new_server)
+    }
 
-        let mut response_xml = quick_xml::Reader::from_str(&response_text);
+    /// Fetches the speedtest.net server list and reads it with
+    /// `parse_list`.
+    pub async fn update_servers(&mut self) -> Result<(), ServersError> {
+        let response_text = Self::fetch_list().await?;
+        self.parse_list(response_text.as_str())
+    }
+
+    /// Replaces the servers with the ones in the `xml` server list.
+    /// Malformed entries are skipped and counted, a list cut short keeps the
+    /// servers read so far and records why; an error is only returned when
+    /// no server could be read.
+    pub fn parse_list(&mut self, xml: &str) -> Result<(), ServersError> {
+        let mut response_xml = quick_xml::Reader::from_str(xml);
         response_xml.config_mut().trim_text(true);
 
         let mut buf = Vec::new();
-        
+        let mut last_error = None;
+        let mut depth = 0;
+        self.servers.clear();
+        self.skipped = 0;
+        self.truncated = None;
+
         loop {
-            match response_xml.read_event_into(&mut buf) {
-                Ok(quick_xml::events::Event::Empty(ref e)) => {
-                    let mut new_server: Server = Server::default();
-                    for att in e.attributes() {
-                        if let Ok(attribute) = att {
-                            let key = std::str::from_utf8(attribute.key.into_inner()).unwrap();
-                            let value = std::str::from_utf8(&attribute.value).unwrap();
-                            match key {
-                                "id" => new_server.id = value.parse().unwrap(),
-                                "name" => new_server.name = value.to_owned(),
-                                "url" => new_server.url = value.to_owned(),
-                                "country" => new_server.country = value.to_owned(),
-                                "sponsor" => new_server.sponsor = value.to_owned(),
-                                "host" => new_server.host = value.to_owned(),
-                                _ => {}
-                            }
+            let event = response_xml.read_event_into(&mut buf);
+            match event {
+                Ok(Event::Start(_)) => depth += 1,
+                Ok(Event::End(_)) => depth -= 1,
+                _ => {}
+            }
+            match event {
+                Ok(Event::Empty(ref e) | Event::Start(ref e)) if e.name().as_ref() == b"server" => {
+                    match Self::parse_server(e) {
+                        Ok(server) => self.add_server(server),
+                        Err(e) => {
+                            self.skipped += 1;
+                            last_error = Some(e);
                         }
                     }
-                    self.servers.push(new_server);
                 }
-                Ok(quick_xml::events::Event::Eof) => {
+                Ok(Event::Eof) => {
+                    if depth > 0 {
+                        self.truncated = Some("the list ends before its closing tags".to_string());
+                    }
+                    break;
+                }
+                // The reader can't recover its position after an error
+                Err(e) => {
+                    self.truncated = Some(e.to_string());
+                    last_error = Some(ServersError::Xml(e));
                     break;
                 }
                 _ => {}
             }
             buf.clear();
         }
-        
+
+        if self.servers.is_empty() {
+            return Err(last_error.unwrap_or(ServersError::Empty));
+        }
         Ok(())
     }
 }
+
+/// Why the server list could not be loaded.
+#[derive(Debug)]
+pub enum ServersError {
+    /// No list URL could be reached
+    Network(reqwest::Error),
+    /// The list URL answered with an error status
+    HttpStatus(StatusCode),
+    /// The list is not well-formed XML
+    Xml(quick_xml::Error),
+    /// A `<server>` entry has a missing or malformed attribute
+    Attribute(String),
+    /// The list holds no servers
+    Empty,
+}
+
+impl fmt::Display for ServersError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServersError::Network(e) => write!(f, "network error: {}", e),
+            ServersError::HttpStatus(status) => write!(f, "server list request failed: {}", status),
+            ServersError::Xml(e) => write!(f, "invalid server list: {}", e),
+            ServersError::Attribute(e) => write!(f, "invalid server entry: {}", e),
+            ServersError::Empty => write!(f, "the server list is empty"),
+        }
+    }
+}
+
+impl error::Error for ServersError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            ServersError::Network(e) => Some(e),
+            ServersError::Xml(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GOOD: &str = r#"<server url="http://a.example/speedtest/upload.php" lat="-34.6" lon="-58.4" name="Buenos Aires" country="Argentina" cc="AR" sponsor="A" id="1" host="a.example:8080" />"#;
+
+    fn list(servers: &[&str]) -> String {
+        format!("<?xml version=\"1.0\"?><settings><servers>{}</servers></settings>", servers.concat())
+    }
+
+    fn parse(xml: &str) -> (Result<(), ServersError>, Servers) {
+        let mut servers = Servers::new();
+        (servers.parse_list(xml), servers)
+    }
+
+    #[test]
+    fn reads_servers() {
+        let (result, servers) = parse(list(&[GOOD]).as_str());
+        assert!(result.is_ok());
+        assert_eq!(servers.get_truncated(), None);
+        let server = &servers.get_servers()[0];
+        assert_eq!((server.id, server.host.as_str(), server.lat, server.lon), (1, "a.example:8080", -34.6, -58.4));
+        assert_eq!(servers.get_skipped(), 0);
+    }
+
+    #[test]
+    fn skips_and_counts_malformed_servers() {
+        let bad_id = r#"<server id="x1" host="b.example:8080" />"#;
+        let bad_lat = r#"<server id="2" lat="north" host="c.example:8080" />"#;
+        let no_host = r#"<server id="3" lat="1.0" lon="2.0" />"#;
+        for bad in [bad_id, bad_lat, no_host] {
+            let (result, servers) = parse(list(&[GOOD, bad]).as_str());
+            assert!(result.is_ok());
+            assert_eq!(servers.get_servers().len(), 1);
+            assert_eq!(servers.get_skipped(), 1);
+        }
+        let (result, servers) = parse(list(&[bad_id, bad_lat, no_host]).as_str());
+        assert!(matches!(result, Err(ServersError::Attribute(_))));
+        assert_eq!(servers.get_skipped(), 3);
+    }
+
+    #[test]
+    fn stops_at_invalid_xml() {
+        let truncated = list(&[GOOD, GOOD]);
+        let truncated = &truncated[..truncated.rfind("<server").unwrap() + 20];
+        let (result, servers) = parse(truncated);
+        assert!(result.is_ok());
+        assert_eq!(servers.get_servers().len(), 1);
+        assert!(servers.get_truncated().is_some());
+
+        // Cut right after a complete entry
+        let (result, servers) = parse(format!("<settings><servers>{}", GOOD).as_str());
+        assert!(result.is_ok());
+        assert_eq!(servers.get_servers().len(), 1);
+        assert!(servers.get_truncated().is_some());
+
+        let (result, servers) = parse(format!("<settings><servers>{}</settings></servers>{}", GOOD, GOOD).as_str());
+        assert!(result.is_ok());
+        assert_eq!(servers.get_servers().len(), 1);
+        assert!(servers.get_truncated().is_some());
+
+        let (result, servers) = parse("<settings><servers><server id=\"1\" host=\"a");
+        assert!(matches!(result, Err(ServersError::Xml(_))));
+        assert!(servers.get_truncated().is_some());
+    }
+
+    #[test]
+    fn empty_list() {
+        let (result, _) = parse(list(&[]).as_str());
+        assert!(matches!(result, Err(ServersError::Empty)));
+    }
+}
@@ -1,57 +1,145 @@
-use std::time::Duration;
+use std::sync::Arc;
 
 use tokio::sync::mpsc;
 
-use crate::http_tester::{HttpDownloadMeasurement, HttpLatencyMeasurement, HttpTester, HttpUploadMeasurement};
+use crate::{backend::SpeedTestBackend, http_tester::{HttpDownloadMeasurement, HttpLatencyMeasurement, HttpThroughputSample, HttpUploadMeasurement}, icmp_tester::IcmpTester, responsiveness::ResponsivenessMeasurement, udp_tester::{UdpMeasurement, UdpTester}};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum HttpTestState {
     Idle,
     MeasuringLatency,
+    MeasuringUdp,
     MeasuringDownload,
     MeasuringUpload,
+    MeasuringResponsiveness,
     Finished,
 }
 
 pub struct HttpTestService {
-    tester: HttpTester,
+    tester: Arc<dyn SpeedTestBackend>,
+    udp_tester: Option<UdpTester>,
+    icmp_tester: Option<IcmpTester>,
     ping_test: HttpLatencyMeasurement,
-    download_test: HttpDownloadMeasurement,
-    upload_test: HttpUploadMeasurement,
+    icmp_test: Result<HttpLatencyMeasurement, String>,
+    udp_test: Result<UdpMeasurement, String>,
+    download_test: Result<HttpDownloadMeasurement, String>,
+    upload_test: Result<HttpUploadMeasurement, String>,
+    responsiveness_test: Result<ResponsivenessMeasurement, String>,
+    download_progress: Option<HttpThroughputSample>,
+    upload_progress: Option<HttpThroughputSample>,
+    client_info: Option<String>,
     state: HttpTestState,
     ping_rx: Option<mpsc::UnboundedReceiver<HttpLatencyMeasurement>>,
-    download_rx: Option<mpsc::UnboundedReceiver<HttpDownloadMeasurement>>,
-    upload_rx: Option<mpsc::UnboundedReceiver<HttpUploadMeasurement>>,
+    icmp_rx: Option<mpsc::UnboundedReceiver<Result<HttpLatencyMeasurement, String>>>,
+    udp_rx: Option<mpsc::UnboundedReceiver<Result<UdpMeasurement, String>>>,
+    download_rx: Option<mpsc::UnboundedReceiver<Result<HttpDownloadMeasurement, String>>>,
+    upload_rx: Option<mpsc::UnboundedReceiver<Result<HttpUploadMeasurement, String>>>,
+    responsiveness_rx: Option<mpsc::UnboundedReceiver<Result<ResponsivenessMeasurement, String>>>,
+    download_progress_rx: Option<mpsc::UnboundedReceiver<HttpThroughputSample>>,
+    upload_progress_rx: Option<mpsc::UnboundedReceiver<HttpThroughputSample>>,
+    client_info_rx: Option<mpsc::UnboundedReceiver<String>>,
 }
 
 impl HttpTestService {
-    pub fn new(tester: HttpTester) -> Self {
+    pub fn new(tester: Arc<dyn SpeedTestBackend>) -> Self {
         HttpTestService {
             tester,
+            udp_tester: None,
+            icmp_tester: None,
             ping_test: HttpLatencyMeasurement::default(),
-            download_test: HttpDownloadMeasurement::default(),
-            upload_test: HttpUploadMeasurement::default(),
+            icmp_test: Ok(HttpLatencyMeasurement::default()),
+            udp_test: Ok(UdpMeasurement::default()),
+            download_test: Ok(HttpDownloadMeasurement::default()),
+            upload_test: Ok(HttpUploadMeasurement::default()),
+            responsiveness_test: Ok(ResponsivenessMeasurement::default()),
+            download_progress: None,
+            upload_progress: None,
+            client_info: None,
             state: HttpTestState::Idle,
             ping_rx: Some(mpsc::unbounded_channel().1),
+            icmp_rx: None,
+            udp_rx: Some(mpsc::unbounded_channel().1),
             download_rx: Some(mpsc::unbounded_channel().1),
             upload_rx: Some(mpsc::unbounded_channel().1),
+            responsiveness_rx: None,
+            download_progress_rx: None,
+            upload_progress_rx: None,
+            client_info_rx: None,
         }
     }
 
-    pub fn set_tester(&mut self, tester: HttpTester) {
+    /// Replaces the backend and asks it for the client info in the
+    /// background.
+    pub fn set_tester(&mut self, tester: Arc<dyn SpeedTestBackend>) {
         self.tester = tester;
+        self.client_info = None;
+        let tester = self.tester.clone();
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.client_info_rx = Some(rx);
+        tokio::spawn(async move {
+            if let Some(info) = tester.client_info().await {
+                let _ = tx.send(info);
+            }
+        });
+    }
+
+    pub fn get_tester(&self) -> &dyn SpeedTestBackend {
+        self.tester.as_ref()
+    }
+
+    /// Adds a UDP test between the latency and the download phase.
+    pub fn set_udp_tester(&mut self, udp_tester: Option<UdpTester>) {
+        self.udp_tester = udp_tester;
+    }
+
+    /// Adds ICMP echo probes next to the latency probes of the backend.
+    pub fn set_icmp_tester(&mut self, icmp_tester: Option<IcmpTester>) {
+        self.icmp_tester = icmp_tester;
+    }
+
+    pub fn get_udp_tester(&self) -> Option<&UdpTester> {
+        self.udp_tester.as_ref()
     }
 
     pub fn get_ping_results(&self) -> &HttpLatencyMeasurement {
         &self.ping_test
     }
 
-    pub fn get_download_results(&self) -> &HttpDownloadMeasurement {
-        &self.download_test
+    /// ICMP latency, or why it could not be measured.
+    pub fn get_icmp_results(&self) -> Result<&HttpLatencyMeasurement, &str> {
+        self.icmp_test.as_ref().map_err(|e| e.as_str())
+    }
+
+    /// UDP result, or why the test failed.
+    pub fn get_udp_results(&self) -> Result<&UdpMeasurement, &str> {
+        self.udp_test.as_ref().map_err(|e| e.as_str())
+    }
+
+    /// Download result, or why the phase failed.
+    pub fn get_download_results(&self) -> Result<&HttpDownloadMeasurement, &str> {
+        self.download_test.as_ref().map_err(|e| e.as_str())
+    }
+
+    /// Upload result, or why the phase failed.
+    pub fn get_upload_results(&self) -> Result<&HttpUploadMeasurement, &str> {
+        self.upload_test.as_ref().map_err(|e| e.as_str())
+    }
+
+    /// Responsiveness score, or why it could not be measured.
+    pub fn get_responsiveness_results(&self) -> Result<&ResponsivenessMeasurement, &str> {
+        self.responsiveness_test.as_ref().map_err(|e| e.as_str())
     }
 
-    pub fn get_upload_results(&self) -> &HttpUploadMeasurement {
-        &self.upload_test
+    pub fn get_download_progress(&self) -> Option<&HttpThroughputSample> {
+        self.download_progress.as_ref()
+    }
+
+    pub fn get_upload_progress(&self) -> Option<&HttpThroughputSample> {
+        self.upload_progress.as_ref()
+    }
+
+    pub fn get_client_info(&self) -> Option<&str> {
+        self.client_info.as_deref()
     }
 
     pub fn get_testing(&self) -> bool {
@@ -71,11 +159,21 @@ impl HttpTestService {
         self.run_current_state();
     }
 
+    /// Runs the responsiveness test on its own, outside the full test.
+    pub fn run_responsiveness(&mut self) {
+        if self.state != HttpTestState::Idle {
+            return;
+        }
+        self.run_responsiveness_test();
+    }
+
     pub fn run_current_state(&mut self) {
         match self.state {
             HttpTestState::MeasuringLatency => self.run_latency_test(),
+            HttpTestState::MeasuringUdp => self.run_udp_test(),
             HttpTestState::MeasuringDownload => self.run_download_test(),
             HttpTestState::MeasuringUpload => self.run_upload_test(),
+            HttpTestState::MeasuringResponsiveness => self.run_responsiveness_test(),
             _ => {
                 self.state = HttpTestState::Idle;
             }
@@ -83,29 +181,61 @@ impl HttpTestService {
     }
 
     pub async fn check_measurments(&mut self) {
-        if let Some(ref mut rx) = self.ping_rx {
-            if let Ok(latency) = rx.try_recv() {
-                self.ping_test = latency;
-                self.state = HttpTestState::MeasuringDownload;
-                self.run_current_state();
-                return ;
-            }
+        if let Some(ref mut rx) = self.client_info_rx
+            && let Ok(info) = rx.try_recv() {
+            self.client_info = Some(info);
         }
-        if let Some(ref mut rx) = self.download_rx {
-            if let Ok(download) = rx.try_recv() {
-                self.download_test = download;
-                self.state = HttpTestState::MeasuringUpload;
-                self.run_current_state();
-                return ;
+        if let Some(ref mut rx) = self.download_progress_rx {
+            while let Ok(sample) = rx.try_recv() {
+                self.download_progress = Some(sample);
             }
         }
-        if let Some(ref mut rx) = self.upload_rx {
-            if let Ok(upload) = rx.try_recv() {
-                self.upload_test = upload;
-                self.state = HttpTestState::Finished;
-                return ;
+        if let Some(ref mut rx) = self.upload_progress_rx {
+            while let Ok(sample) = rx.try_recv() {
+                self.upload_progress = Some(sample);
             }
         }
+        if let Some(ref mut rx) = self.icmp_rx
+            && let Ok(icmp) = rx.try_recv() {
+            self.icmp_test = icmp;
+        }
+        if let Some(ref mut rx) = self.ping_rx
+            && let Ok(latency) = rx.try_recv() {
+            self.ping_test = latency;
+            self.state = if self.udp_tester.is_some() {
+                HttpTestState::MeasuringUdp
+            } else {
+                HttpTestState::MeasuringDownload
+            };
+            self.run_current_state();
+            return ;
+        }
+        if let Some(ref mut rx) = self.udp_rx
+            && let Ok(udp) = rx.try_recv() {
+            self.udp_test = udp;
+            self.state = HttpTestState::MeasuringDownload;
+            self.run_current_state();
+            return ;
+        }
+        if let Some(ref mut rx) = self.download_rx
+            && let Ok(download) = rx.try_recv() {
+            self.download_test = download;
+            self.state = HttpTestState::MeasuringUpload;
+            self.run_current_state();
+            return ;
+        }
+        if let Some(ref mut rx) = self.upload_rx
+            && let Ok(upload) = rx.try_recv() {
+            self.upload_test = upload;
+            self.state = HttpTestState::Finished;
+            return ;
+        }
+        if let Some(ref mut rx) = self.responsiveness_rx
+            && let Ok(responsiveness) = rx.try_recv() {
+            self.responsiveness_test = responsiveness;
+            self.state = HttpTestState::Finished;
+            return ;
+        }
         if self.state == HttpTestState::Finished {
             self.state = HttpTestState::Idle;
         }
@@ -114,20 +244,40 @@ impl HttpTestService {
     pub fn run_latency_test(&mut self) {
         self.state = HttpTestState::MeasuringLatency;
         let tester = self.tester.clone();
+        let icmp_tester = self.icmp_tester.clone();
         let (tx, rx) = mpsc::unbounded_channel();
         self.ping_rx = Some(rx);
+        let (icmp_tx, icmp_rx) = mpsc::unbounded_channel();
+        self.icmp_rx = Some(icmp_rx);
         tokio::spawn(async move {
-            let latency = tester.measure_latency_multiple(20).await;
-            match latency {
-                Ok(latency) => {
-                    if tx.send(latency).is_err() {
-                        let _ = tx.send(HttpLatencyMeasurement::default());
-                    }
-                }
-                Err(_) => {
-                    let _ = tx.send(HttpLatencyMeasurement::default());
+            let icmp = async {
+                match icmp_tester {
+                    Some(icmp_tester) => icmp_tester.measure_latency_multiple(20).await.map_err(|e| e.to_string()),
+                    None => Ok(HttpLatencyMeasurement::default()),
                 }
+            };
+            let (mut latency, icmp) = tokio::join!(tester.measure_latency_multiple(20), icmp);
+            latency.timings = tester.measure_timings(5).await;
+            // Sent first so it is in place when the phase is seen to end
+            let _ = icmp_tx.send(icmp);
+            let _ = tx.send(latency);
+        });
+    }
+
+    pub fn run_udp_test(&mut self) {
+        self.state = HttpTestState::MeasuringUdp;
+        let udp_tester = match self.udp_tester.clone() {
+            Some(udp_tester) => udp_tester,
+            None => {
+                self.run_download_test();
+                return;
             }
+        };
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.udp_rx = Some(rx);
+        tokio::spawn(async move {
+            let udp = udp_tester.measure().await.map_err(|e| e.to_string());
+            let _ = tx.send(udp);
         });
     }
 
@@ -136,19 +286,18 @@ impl HttpTestService {
         let tester = self.tester.clone();
         let (tx, rx) = mpsc::unbounded_channel();
         self.download_rx = Some(rx);
+        let (progress_tx, progress_rx) = mpsc::unbounded_channel();
+        self.download_progress = None;
+        self.download_progress_rx = Some(progress_rx);
         tokio::spawn(async move {
-            let download = tester.measure_download().await;
-            match download {
-                Ok(download) => {
-                    if tx.send(download).is_err() {
-                        let _ = tx.send(HttpDownloadMeasurement::default());
-                    }
-                }
-                Err(_) => {
-                    let _ = tx.send(HttpDownloadMeasurement::default());
-                }
-            }
-            
+            let (download, loaded_latency) = tester.measure_latency_while(tester.measure_download(progress_tx)).await;
+            let download = download
+                .map(|mut download| {
+                    download.loaded_latency = loaded_latency;
+                    download
+                })
+                .map_err(|e| e.to_string());
+            let _ = tx.send(download);
         });
     }
 
@@ -157,18 +306,32 @@ impl HttpTestService {
         let tester = self.tester.clone();
         let (tx, rx) = mpsc::unbounded_channel();
         self.upload_rx = Some(rx);
+        let (progress_tx, progress_rx) = mpsc::unbounded_channel();
+        self.upload_progress = None;
+        self.upload_progress_rx = Some(progress_rx);
         tokio::spawn(async move {
-            let response = tester.measure_upload().await;
-            match response {
-                Ok(upload) => {
-                    if tx.send(upload).is_err() {
-                        let _ = tx.send(HttpUploadMeasurement::default());
-                    }
-                }
-                Err(_) => {
-                    let _ = tx.send(HttpUploadMeasurement::default());
-                }
-            }
+            let (upload, loaded_latency) = tester.measure_latency_while(tester.measure_upload(progress_tx)).await;
+            let upload = upload
+                .map(|mut upload| {
+                    upload.loaded_latency = loaded_latency;
+                    upload
+                })
+                .map_err(|e| e.to_string());
+            let _ = tx.send(upload);
+        });
+    }
+
+    pub fn run_responsiveness_test(&mut self) {
+        self.state = HttpTestState::MeasuringResponsiveness;
+        let tester = self.tester.clone();
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.responsiveness_rx = Some(rx);
+        let (progress_tx, progress_rx) = mpsc::unbounded_channel();
+        self.download_progress = None;
+        self.download_progress_rx = Some(progress_rx);
+        tokio::spawn(async move {
+            let responsiveness = tester.measure_responsiveness(progress_tx).await.map_err(|e| e.to_string());
+            let _ = tx.send(responsiveness);
         });
     }
 }
\ No newline at end of file
@@ -0,0 +1,305 @@
+use bytes::Bytes;
+use futures_util::future::BoxFuture;
+use std::{io::Error, time::{Duration, Instant}};
+use tokio::{io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader}, net::TcpStream, sync::mpsc, task::JoinSet, time::{timeout, timeout_at}};
+
+use crate::{backend::SpeedTestBackend, http_tester::{join_streams, random_payload, AdaptiveLadder, ByteCounter, HttpDownloadMeasurement, HttpThroughputSample, HttpUploadMeasurement, ThroughputMeter, TransferSettings, CHUNK_SIZES}};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+const WRITE_PIECE_SIZE: usize = 64 * 1024;
+
+/// Client for the line based TCP protocol speedtest.net servers speak on
+/// the `host` port of the server list (usually 8080):
+///
+/// - `HI` is answered with `HELLO <version>`
+/// - `PING <ms>` is answered with `PONG <ms>`
+/// - `DOWNLOAD <n>` makes the server send `n` bytes, `DOWNLOAD ` first and a newline last
+/// - `UPLOAD <n> 0` is followed by the client sending `n` bytes in total, command
+///   line included and a newline last, and is answered with `OK <n> <ms>`
+#[derive(Debug, Clone)]
+pub struct TcpTester {
+    pub host: String, // host:port
+    pub settings: TransferSettings,
+}
+
+impl TcpTester {
+    pub fn new(host: &str, settings: TransferSettings) -> Self {
+        TcpTester {
+            host: host.to_string(),
+            settings,
+        }
+    }
+
+    /// Opens a connection and completes the `HI`/`HELLO` greeting.
+    async fn connect(&self) -> Result<BufReader<TcpStream>, Error> {
+        let stream = match timeout(CONNECT_TIMEOUT, TcpStream::connect(self.host.as_str())).await {
+            Ok(stream) => stream?,
+            Err(_) => return Err(Error::other(format!("Connection to {} timed out", self.host))),
+        };
+        stream.set_nodelay(true)?;
+        let mut stream = BufReader::new(stream);
+        stream.get_mut().write_all(b"HI\n").await?;
+        let greeting = Self::read_line(&mut stream).await?;
+        if !greeting.starts_with("HELLO") {
+            return Err(Error::other(format!("Unexpected greeting: {}", greeting)));
+        }
+        Ok(stream)
+    }
+
+    async fn read_line(stream: &mut BufReader<TcpStream>) -> Result<String, Error> {
+        let mut line = String::new();
+        match timeout(CONNECT_TIMEOUT, stream.read_line(&mut line)).await {
+            Ok(Ok(0)) => Err(Error::other("Connection closed by server")),
+            Ok(Ok(_)) => Ok(line.trim_end().to_string()),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(Error::other("Timed out waiting for the server")),
+        }
+    }
+
+    /// Round trip of one `PING` on a fresh connection; the greeting is not
+    /// part of the measured time.
+    pub async fn measure_latency(&self) -> Result<f64, Error> {
+        let mut stream = self.connect().await?;
+        let start = Instant::now();
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        stream.get_mut().write_all(format!("PING {}\n", timestamp).as_bytes()).await?;
+        let reply = Self::read_line(&mut stream).await?;
+        let duration = start.elapsed();
+        if !reply.starts_with("PONG") {
+            return Err(Error::other(format!("Unexpected reply: {}", reply)));
+        }
+        Ok(duration.as_secs_f64() * 1000.0)
+    }
+
+    /// Downloads over `connections` parallel connections until
+    /// `test_duration` runs out, each one adapting its `DOWNLOAD` size along
+    /// `CHUNK_SIZES`.
+    pub async fn measure_download(&self, progress: mpsc::UnboundedSender<HttpThroughputSample>) -> Result<HttpDownloadMeasurement, Error> {
+        let meter = ThroughputMeter::start(progress);
+        let start = Instant::now();
+        let deadline = start + self.settings.test_duration;
+        let mut streams = JoinSet::new();
+        for _ in 0..self.settings.connections.max(1) {
+            let tester = self.clone();
+            let counter = meter.counter();
+            let mut ladder = AdaptiveLadder::new(CHUNK_SIZES.to_vec());
+            streams.spawn(async move {
+                let mut stream = tester.connect().await?;
+                while Instant::now() < deadline {
+                    let size = ladder.current();
+                    let request_start = Instant::now();
+                    if !Self::download_chunk(&mut stream, size, deadline, &counter).await? {
+                        break;
+                    }
+                    ladder.record(request_start.elapsed());
+                }
+                Ok(())
+            });
+        }
+
+        let error = join_streams(streams).await;
+        let (bytes, samples) = meter.finish().await;
+        let duration = start.elapsed().min(self.settings.test_duration);
+        HttpDownloadMeasurement::from_transfer(bytes, duration, samples, self.settings.warmup, self.settings.trim_fraction, error)
+    }
+
+    /// Reads one `DOWNLOAD` reply, returning `false` when the deadline cut it
+    /// short.
+    async fn download_chunk(stream: &mut BufReader<TcpStream>, size: usize, deadline: Instant, counter: &ByteCounter) -> Result<bool, Error> {
+        stream.get_mut().write_all(format!("DOWNLOAD {}\n", size).as_bytes()).await?;
+        let mut buf = vec![0u8; READ_BUFFER_SIZE];
+        let mut remaining = size;
+        while remaining > 0 {
+            let to_read = remaining.min(buf.len());
+            match timeout_at(deadline.into(), stream.read(&mut buf[..to_read])).await {
+                Ok(Ok(0)) => return Err(Error::other("Connection closed by server")),
+                Ok(Ok(n)) => {
+                    counter.add(n as u64);
+                    remaining -= n;
+                }
+                Ok(Err(e)) => return Err(e),
+                Err(_) => return Ok(false),
+            }
+        }
+        Ok(true)
+    }
+
+    /// Uploads over `connections` parallel connections until `test_duration`
+    /// runs out, each one adapting its `UPLOAD` size along `CHUNK_SIZES`.
+    pub async fn measure_upload(&self, progress: mpsc::UnboundedSender<HttpThroughputSample>) -> Result<HttpUploadMeasurement, Error> {
+        let payload = Self::printable_payload(CHUNK_SIZES[CHUNK_SIZES.len() - 1]);
+
+        let meter = ThroughputMeter::start(progress);
+        let start = Instant::now();
+        let deadline = start + self.settings.test_duration;
+        let mut streams = JoinSet::new();
+        for _ in 0..self.settings.connections.max(1) {
+            let tester = self.clone();
+            let payload = payload.clone();
+            let counter = meter.counter();
+            let mut ladder = AdaptiveLadder::new(CHUNK_SIZES.to_vec());
+            streams.spawn(async move {
+                let mut stream = tester.connect().await?;
+                while Instant::now() < deadline {
+                    let size = ladder.current();
+                    let request_start = Instant::now();
+                    match timeout_at(deadline.into(), Self::upload_chunk(&mut stream, &payload, size, &counter)).await {
+                        Ok(result) => result?,
+                        Err(_) => break,
+                    }
+                    ladder.record(request_start.elapsed());
+                }
+                Ok(())
+            });
+        }
+
+        let error = join_streams(streams).await;
+        let (bytes, samples) = meter.finish().await;
+        let duration = start.elapsed().min(self.settings.test_duration);
+        HttpUploadMeasurement::from_transfer(bytes, duration, samples, self.settings.warmup, self.settings.trim_fraction, error)
+    }
+
+    /// Random letters instead of raw bytes so the payload can never contain
+    /// the newline that ends an upload early.
+    fn printable_payload(size: usize) -> Bytes {
+        let data: Vec<u8> = random_payload(size).iter().map(|byte| b'A' + byte % 26).collect();
+        Bytes::from(data)
+    }
+
+    async fn upload_chunk(stream: &mut BufReader<TcpStream>, payload: &Bytes, size: usize, counter: &ByteCounter) -> Result<(), Error> {
+        let command = format!("UPLOAD {} 0\n", size);
+        stream.get_mut().write_all(command.as_bytes()).await?;
+        counter.add(command.len() as u64);
+
+        let body = payload.slice(..size.saturating_sub(command.len() + 1));
+        for piece in body.chunks(WRITE_PIECE_SIZE) {
+            stream.get_mut().write_all(piece).await?;
+            counter.add(piece.len() as u64);
+        }
+        stream.get_mut().write_all(b"\n").await?;
+        counter.add(1);
+
+        let reply = Self::read_line(stream).await?;
+        if !reply.starts_with("OK") {
+            return Err(Error::other(format!("Unexpected reply: {}", reply)));
+        }
+        Ok(())
+    }
+}
+
+impl SpeedTestBackend for TcpTester {
+    fn name(&self) -> &str {
+        "TCP"
+    }
+
+    fn measure_latency(&self) -> BoxFuture<'_, Result<f64, Error>> {
+        Box::pin(TcpTester::measure_latency(self))
+    }
+
+    fn measure_download(&self, progress: mpsc::UnboundedSender<HttpThroughputSample>) -> BoxFuture<'_, Result<HttpDownloadMeasurement, Error>> {
+        Box::pin(TcpTester::measure_download(self, progress))
+    }
+
+    fn measure_upload(&self, progress: mpsc::UnboundedSender<HttpThroughputSample>) -> BoxFuture<'_, Result<HttpUploadMeasurement, Error>> {
+        Box::pin(TcpTester::measure_upload(self, progress))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tokio::net::TcpListener;
+
+    /// Minimal speedtest.net TCP server that records the total size of every
+    /// upload it receives, command line and final newline included.
+    async fn stand_in() -> (String, Arc<Mutex<Vec<(usize, usize)>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let uploads = Arc::new(Mutex::new(Vec::new()));
+        let recorded = uploads.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let uploads = recorded.clone();
+                tokio::spawn(async move {
+                    let mut stream = BufReader::new(stream);
+                    let mut line = String::new();
+                    while stream.read_line(&mut line).await.unwrap_or(0) > 0 {
+                        let words: Vec<&str> = line.split_whitespace().collect();
+                        let reply = match words.as_slice() {
+                            ["HI"] => b"HELLO 2.9 (2.9.0) 2024-01-01.0000.0000000\n".to_vec(),
+                            ["PING", time] => format!("PONG {}\n", time).into_bytes(),
+                            ["DOWNLOAD", size] => {
+                                let size: usize = size.parse().unwrap();
+                                let mut reply = b"DOWNLOAD ".to_vec();
+                                reply.resize(size - 1, b'x');
+                                reply.push(b'\n');
+                                reply
+                            }
+                            ["UPLOAD", size, "0"] => {
+                                let size: usize = size.parse().unwrap();
+                                let mut body = Vec::new();
+                                if stream.read_until(b'\n', &mut body).await.is_err() {
+                                    return;
+                                }
+                                uploads.lock().unwrap().push((size, line.len() + body.len()));
+                                format!("OK {} 0\n", size).into_bytes()
+                            }
+                            _ => b"ERROR\n".to_vec(),
+                        };
+                        if stream.get_mut().write_all(&reply).await.is_err() {
+                            return;
+                        }
+                        line.clear();
+                    }
+                });
+            }
+        });
+        (address, uploads)
+    }
+
+    fn tester(host: &str) -> TcpTester {
+        TcpTester::new(host, TransferSettings {
+            connections: 2,
+            test_duration: Duration::from_millis(1500),
+            warmup: Duration::ZERO,
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn measures_latency() {
+        let (address, _) = stand_in().await;
+        let latency = tester(address.as_str()).measure_latency().await.unwrap();
+        assert!(latency > 0.0);
+    }
+
+    #[tokio::test]
+    async fn downloads_and_uploads() {
+        let (address, uploads) = stand_in().await;
+        let tester = tester(address.as_str());
+        let download = tester.measure_download(mpsc::unbounded_channel().0).await.unwrap();
+        assert!(download.bits > 0);
+        let upload = tester.measure_upload(mpsc::unbounded_channel().0).await.unwrap();
+        assert!(upload.bits > 0);
+        assert!(!uploads.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn upload_size_includes_command_and_newline() {
+        let (address, uploads) = stand_in().await;
+        let mut stream = tester(address.as_str()).connect().await.unwrap();
+        let payload = TcpTester::printable_payload(CHUNK_SIZES[0]);
+        let counter = ByteCounter::default();
+        for size in [CHUNK_SIZES[0], 1000] {
+            TcpTester::upload_chunk(&mut stream, &payload, size, &counter).await.unwrap();
+        }
+        assert_eq!(*uploads.lock().unwrap(), vec![(CHUNK_SIZES[0], CHUNK_SIZES[0]), (1000, 1000)]);
+        assert_eq!(counter.get(), (CHUNK_SIZES[0] + 1000) as u64);
+    }
+}
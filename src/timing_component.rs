@@ -0,0 +1,58 @@
+use std::time::Duration;
+
+use ratatui::{layout::Constraint, style::{Color, Style, Stylize}, text::Line, widgets::{Block, Row, Table, Widget}};
+
+use crate::{http_tester::HttpTimingBreakdown, servers::Server};
+
+#[derive(Default, Clone)]
+pub struct TimingComponent {
+    timings: Vec<HttpTimingBreakdown>,
+    server: Server,
+}
+
+impl TimingComponent {
+    pub fn set_timings(&mut self, timings: Vec<HttpTimingBreakdown>) {
+        self.timings = timings;
+    }
+    pub fn set_server(&mut self, server: Server) {
+        self.server = server;
+    }
+
+    fn row(label: String, timing: &HttpTimingBreakdown) -> Row<'static> {
+        let ms = |duration: Duration| format!("{:.2} ms", duration.as_secs_f64() * 1000.0);
+        Row::new(vec![
+            label,
+            ms(timing.dns),
+            ms(timing.connect),
+            timing.tls.map(ms).unwrap_or_else(|| "-".to_string()),
+            ms(timing.ttfb),
+            ms(timing.total),
+        ])
+    }
+}
+
+impl Widget for &TimingComponent {
+    fn render(self, area: ratatui::layout::Rect, buf: &mut ratatui::buffer::Buffer) {
+        let header = Row::new(vec!["Probe", "DNS", "Connect", "TLS", "TTFB", "Total"]).bold();
+        let mut rows: Vec<Row> = self.timings.iter()
+            .enumerate()
+            .map(|(i, timing)| TimingComponent::row(format!("#{}", i + 1), timing))
+            .collect();
+        if let Some(average) = HttpTimingBreakdown::average(&self.timings) {
+            rows.push(TimingComponent::row("Average".to_string(), &average).bold().yellow());
+        }
+
+        let mut block = Block::bordered()
+            .border_style(Style::default().fg(Color::Blue))
+            .title(Line::from("Connection Details").bold());
+        if self.server.is_listed() {
+            block = block.title_bottom(Line::from(format!("{} {}", self.server.label(), self.server.location())).right_aligned());
+        }
+
+        let table = Table::new(rows, [Constraint::Ratio(1, 6); 6])
+            .header(header)
+            .block(block);
+
+        Widget::render(table, area, buf);
+    }
+}
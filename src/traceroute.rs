@@ -0,0 +1,285 @@
+use std::{io::Error, net::{IpAddr, SocketAddr}, time::{Duration, Instant}};
+use tokio::{net::{lookup_host, UdpSocket}, sync::mpsc, task::{JoinHandle, JoinSet}, time::timeout};
+
+/// Destination port of the probe with TTL 1, the one classic traceroute uses.
+const FIRST_PORT: u16 = 33434;
+const MAX_HOPS: u8 = 30;
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+const ROUND_INTERVAL: Duration = Duration::from_secs(1);
+const PROBE_PAYLOAD: &[u8] = b"speedtest-tui traceroute";
+
+/// Running statistics of one hop, like a row of mtr.
+#[derive(Debug, Default, Clone)]
+pub struct HopStats {
+    pub ttl: u8,
+    pub address: Option<IpAddr>,
+    pub sent: usize,
+    pub received: usize,
+    pub last: f64, // ms
+    pub best: f64, // ms
+    pub worst: f64, // ms
+    pub avg: f64, // ms
+    sum_squares: f64,
+}
+
+impl HopStats {
+    fn new(ttl: u8) -> Self {
+        HopStats { ttl, ..Default::default() }
+    }
+
+    fn record(&mut self, reply: Option<(IpAddr, f64)>) {
+        self.sent += 1;
+        let (address, rtt) = match reply {
+            Some(reply) => reply,
+            None => return,
+        };
+        self.address = Some(address);
+        self.best = if self.received == 0 { rtt } else { self.best.min(rtt) };
+        self.worst = self.worst.max(rtt);
+        self.last = rtt;
+        self.received += 1;
+        self.avg += (rtt - self.avg) / self.received as f64;
+        self.sum_squares += rtt * rtt;
+    }
+
+    pub fn loss_percent(&self) -> f64 {
+        if self.sent == 0 {
+            0.0
+        } else {
+            (self.sent - self.received) as f64 / self.sent as f64 * 100.0
+        }
+    }
+
+    pub fn std_dev(&self) -> f64 {
+        if self.received == 0 {
+            return 0.0;
+        }
+        (self.sum_squares / self.received as f64 - self.avg * self.avg).max(0.0).sqrt()
+    }
+}
+
+/// What came back for one probe.
+enum ProbeReply {
+    /// A router on the way said the TTL ran out
+    Hop(IpAddr, f64),
+    /// The destination, or a router giving up on it, said it is unreachable
+    End(IpAddr, f64),
+    Lost,
+}
+
+/// Hop by hop path to `host`, probed with UDP datagrams of increasing TTL.
+/// The ICMP errors they cause are read from the socket error queue, so no
+/// privileges are needed, the way `tracepath` does it.
+#[derive(Debug, Clone)]
+pub struct Traceroute {
+    pub host: String,
+}
+
+/// A traceroute running in the background, stopped when dropped.
+pub struct TracerouteRun {
+    pub updates: mpsc::UnboundedReceiver<Result<Vec<HopStats>, String>>,
+    task: JoinHandle<()>,
+}
+
+impl Drop for TracerouteRun {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl Traceroute {
+    pub fn new(host: &str) -> Self {
+        Traceroute { host: host.to_string() }
+    }
+
+    /// Probes the path every `ROUND_INTERVAL` and sends the updated hops
+    /// after each round, until the returned run is dropped.
+    pub fn start(&self) -> TracerouteRun {
+        let (tx, updates) = mpsc::unbounded_channel();
+        let host = self.host.clone();
+        let task = tokio::spawn(async move {
+            if let Err(e) = Self::run(host.as_str(), &tx).await {
+                let _ = tx.send(Err(e.to_string()));
+            }
+        });
+        TracerouteRun { updates, task }
+    }
+
+    async fn run(host: &str, tx: &mpsc::UnboundedSender<Result<Vec<HopStats>, String>>) -> Result<(), Error> {
+        let address = lookup_host((host, 0)).await?
+            .next()
+            .ok_or_else(|| Error::other(format!("No address found for {}", host)))?;
+        let mut hops: Vec<HopStats> = (1..=MAX_HOPS).map(HopStats::new).collect();
+        // Hops past the one that ended the path are not probed again
+        let mut path_length = MAX_HOPS;
+        loop {
+            let round_start = Instant::now();
+            let mut probes = JoinSet::new();
+            for ttl in 1..=path_length {
+                probes.spawn(async move { (ttl, Self::probe(address, ttl).await) });
+            }
+            while let Some(result) = probes.join_next().await {
+                let (ttl, reply) = match result {
+                    Ok((ttl, Ok(reply))) => (ttl, reply),
+                    Ok((_, Err(e))) => return Err(e),
+                    Err(e) => return Err(Error::other(format!("Probe task failed: {}", e))),
+                };
+                let hop = &mut hops[ttl as usize - 1];
+                match reply {
+                    ProbeReply::Hop(address, rtt) => hop.record(Some((address, rtt))),
+                    ProbeReply::End(address, rtt) => {
+                        hop.record(Some((address, rtt)));
+                        path_length = path_length.min(ttl);
+                    }
+                    ProbeReply::Lost => hop.record(None),
+                }
+            }
+
+            let shown = hops.iter()
+                .take(path_length as usize)
+                .rposition(|hop| hop.received > 0)
+                .map_or(1, |last| last + 1);
+            if tx.send(Ok(hops[..shown].to_vec())).is_err() {
+                return Ok(());
+            }
+            tokio::time::sleep(ROUND_INTERVAL.saturating_sub(round_start.elapsed())).await;
+        }
+    }
+
+    async fn probe(address: SocketAddr, ttl: u8) -> Result<ProbeReply, Error> {
+        let local = if address.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = UdpSocket::bind(local).await?;
+        error_queue::enable(&socket, address.is_ipv4())?;
+        if address.is_ipv4() {
+            socket.set_ttl(ttl as u32)?;
+        } else {
+            socket2::SockRef::from(&socket).set_unicast_hops_v6(ttl as u32)?;
+        }
+
+        let target = SocketAddr::new(address.ip(), FIRST_PORT + ttl as u16);
+        let start = Instant::now();
+        socket.send_to(PROBE_PAYLOAD, target).await?;
+        match timeout(PROBE_TIMEOUT, error_queue::next_icmp_error(&socket)).await {
+            Ok(Ok((from, time_exceeded))) => {
+                let rtt = start.elapsed().as_secs_f64() * 1000.0;
+                if time_exceeded {
+                    Ok(ProbeReply::Hop(from, rtt))
+                } else {
+                    Ok(ProbeReply::End(from, rtt))
+                }
+            }
+            Ok(Err(e)) => Err(e),
+            Err(_) => Ok(ProbeReply::Lost),
+        }
+    }
+}
+
+/// Reading the ICMP errors a UDP socket caused, through `IP_RECVERR`.
+#[cfg(target_os = "linux")]
+mod error_queue {
+    use std::{io::{Error, ErrorKind}, mem, net::{IpAddr, Ipv4Addr, Ipv6Addr}, os::fd::AsRawFd};
+    use tokio::{io::Interest, net::UdpSocket};
+
+    const ICMP_TIME_EXCEEDED: u8 = 11;
+    const ICMPV6_TIME_EXCEEDED: u8 = 3;
+
+    pub fn enable(socket: &UdpSocket, ipv4: bool) -> Result<(), Error> {
+        let (level, option) = if ipv4 {
+            (libc::SOL_IP, libc::IP_RECVERR)
+        } else {
+            (libc::SOL_IPV6, libc::IPV6_RECVERR)
+        };
+        let on: libc::c_int = 1;
+        // SAFETY: `on` outlives the call and its size is passed along
+        let result = unsafe {
+            libc::setsockopt(socket.as_raw_fd(), level, option, &on as *const _ as *const libc::c_void, mem::size_of::<libc::c_int>() as libc::socklen_t)
+        };
+        if result < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Waits for the next ICMP error on `socket` and returns the address
+    /// that sent it and whether it was a time exceeded message.
+    pub async fn next_icmp_error(socket: &UdpSocket) -> Result<(IpAddr, bool), Error> {
+        loop {
+            socket.ready(Interest::ERROR).await?;
+            match socket.try_io(Interest::ERROR, || read(socket.as_raw_fd())) {
+                Ok(Some(error)) => return Ok(error),
+                Ok(None) => continue,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Takes one message off the error queue; `None` when it was not an
+    /// ICMP error.
+    fn read(fd: libc::c_int) -> Result<Option<(IpAddr, bool)>, Error> {
+        let mut data = [0u8; 512];
+        let mut control = [0u8; 512];
+        let mut iov = libc::iovec { iov_base: data.as_mut_ptr() as *mut libc::c_void, iov_len: data.len() };
+        // SAFETY: all-zero is a valid msghdr, the pointers set below outlive the call
+        let mut message: libc::msghdr = unsafe { mem::zeroed() };
+        message.msg_iov = &mut iov;
+        message.msg_iovlen = 1;
+        message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        message.msg_controllen = control.len();
+        // SAFETY: `message` describes buffers that live until the call returns
+        if unsafe { libc::recvmsg(fd, &mut message, libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT) } < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        // SAFETY: the control messages were filled in by recvmsg above
+        let mut header = unsafe { libc::CMSG_FIRSTHDR(&message) };
+        while !header.is_null() {
+            let (level, kind) = unsafe { ((*header).cmsg_level, (*header).cmsg_type) };
+            if (level == libc::SOL_IP && kind == libc::IP_RECVERR) || (level == libc::SOL_IPV6 && kind == libc::IPV6_RECVERR) {
+                // SAFETY: a RECVERR message holds a sock_extended_err followed by the offender address
+                let error = unsafe { &*(libc::CMSG_DATA(header) as *const libc::sock_extended_err) };
+                let offender = unsafe { (error as *const libc::sock_extended_err).add(1) as *const libc::sockaddr };
+                let time_exceeded = match error.ee_origin {
+                    libc::SO_EE_ORIGIN_ICMP => error.ee_type == ICMP_TIME_EXCEEDED,
+                    libc::SO_EE_ORIGIN_ICMP6 => error.ee_type == ICMPV6_TIME_EXCEEDED,
+                    _ => return Ok(None),
+                };
+                return Ok(unsafe { address(offender) }.map(|address| (address, time_exceeded)));
+            }
+            header = unsafe { libc::CMSG_NXTHDR(&message, header) };
+        }
+        Ok(None)
+    }
+
+    /// # Safety
+    /// `address` must point to a sockaddr of the size its family implies.
+    unsafe fn address(address: *const libc::sockaddr) -> Option<IpAddr> {
+        unsafe {
+            match (*address).sa_family as libc::c_int {
+                libc::AF_INET => {
+                    let address = &*(address as *const libc::sockaddr_in);
+                    Some(IpAddr::V4(Ipv4Addr::from(u32::from_be(address.sin_addr.s_addr))))
+                }
+                libc::AF_INET6 => {
+                    let address = &*(address as *const libc::sockaddr_in6);
+                    Some(IpAddr::V6(Ipv6Addr::from(address.sin6_addr.s6_addr)))
+                }
+                _ => None,
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod error_queue {
+    use std::{io::{Error, ErrorKind}, net::IpAddr};
+    use tokio::net::UdpSocket;
+
+    pub fn enable(_socket: &UdpSocket, _ipv4: bool) -> Result<(), Error> {
+        Err(Error::new(ErrorKind::Unsupported, "Traceroute is only supported on Linux"))
+    }
+
+    pub async fn next_icmp_error(_socket: &UdpSocket) -> Result<(IpAddr, bool), Error> {
+        Err(Error::new(ErrorKind::Unsupported, "Traceroute is only supported on Linux"))
+    }
+}
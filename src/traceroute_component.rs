@@ -0,0 +1,78 @@
+use ratatui::{layout::Constraint, style::{Color, Style, Stylize}, text::Line, widgets::{Block, Paragraph, Row, Table, Widget}};
+
+use crate::traceroute::HopStats;
+
+#[derive(Default, Clone)]
+pub struct TracerouteComponent {
+    target: String,
+    hops: Vec<HopStats>,
+    error: Option<String>,
+}
+
+impl TracerouteComponent {
+    pub fn set_target(&mut self, target: String) {
+        self.target = target;
+    }
+    pub fn set_hops(&mut self, hops: Vec<HopStats>) {
+        self.hops = hops;
+        self.error = None;
+    }
+    pub fn set_error(&mut self, error: String) {
+        self.error = Some(error);
+    }
+
+    fn row(hop: &HopStats) -> Row<'static> {
+        let ms = |value: f64| if hop.received == 0 { "-".to_string() } else { format!("{:.1}", value) };
+        let row = Row::new(vec![
+            hop.ttl.to_string(),
+            hop.address.map_or("???".to_string(), |address| address.to_string()),
+            format!("{:.1}%", hop.loss_percent()),
+            hop.sent.to_string(),
+            ms(hop.last),
+            ms(hop.avg),
+            ms(hop.best),
+            ms(hop.worst),
+            ms(hop.std_dev()),
+        ]);
+        if hop.received < hop.sent {
+            row.fg(if hop.received == 0 { Color::DarkGray } else { Color::Yellow })
+        } else {
+            row
+        }
+    }
+}
+
+impl Widget for &TracerouteComponent {
+    fn render(self, area: ratatui::layout::Rect, buf: &mut ratatui::buffer::Buffer) {
+        let block = Block::bordered()
+            .border_style(Style::default().fg(Color::Blue))
+            .title(Line::from(format!("Path to {}", self.target)).bold());
+
+        if let Some(error) = &self.error {
+            Paragraph::new(Line::from(format!("Traceroute failed: {}", error)).red().centered())
+                .block(block)
+                .render(area, buf);
+            return;
+        }
+
+        let header = Row::new(vec!["Hop", "Host", "Loss", "Sent", "Last", "Avg", "Best", "Worst", "StDev"]).bold();
+        let rows: Vec<Row> = self.hops.iter().map(TracerouteComponent::row).collect();
+        let widths = [
+            Constraint::Length(4),
+            Constraint::Min(16),
+            Constraint::Length(7),
+            Constraint::Length(5),
+            Constraint::Length(7),
+            Constraint::Length(7),
+            Constraint::Length(7),
+            Constraint::Length(7),
+            Constraint::Length(7),
+        ];
+
+        let table = Table::new(rows, widths)
+            .header(header)
+            .block(block);
+
+        Widget::render(table, area, buf);
+    }
+}
@@ -0,0 +1,83 @@
+use crate::udp_tester::UdpMeasurement;
+use ratatui::{style::{Color, Style, Stylize}, text::{Line, Text}, widgets::{Block, Widget}};
+
+#[derive(Clone)]
+pub struct UdpComponent {
+    udp_measurement: Result<UdpMeasurement, String>,
+    active: bool,
+}
+
+impl Default for UdpComponent {
+    fn default() -> Self {
+        UdpComponent {
+            udp_measurement: Ok(UdpMeasurement::default()),
+            active: false,
+        }
+    }
+}
+
+impl UdpComponent {
+    pub fn set_udp_measurement(&mut self, udp: Result<UdpMeasurement, String>) {
+        self.udp_measurement = udp;
+    }
+    pub fn set_active(&mut self, active: bool) {
+        self.active = active;
+    }
+}
+
+impl UdpComponent {
+    /// Formats a statistic, or a placeholder until an echo came back.
+    fn format_ms(udp: &UdpMeasurement, value: f64) -> String {
+        if udp.received == 0 {
+            "--".to_string()
+        } else {
+            format!("{:.2}", value)
+        }
+    }
+
+    fn lines(udp: &UdpMeasurement) -> Text<'static> {
+        Text::from(vec![
+            Line::from(format!("Round Trip: {} ms", UdpComponent::format_ms(udp, udp.rtt.avg)))
+                .bold()
+                .blue()
+                .centered(),
+            Line::from(format!(
+                "Jitter up: {} ms | down: {} ms",
+                UdpComponent::format_ms(udp, udp.upstream_jitter), UdpComponent::format_ms(udp, udp.downstream_jitter)
+            ))
+                .bold()
+                .yellow()
+                .centered(),
+            Line::from(format!("Lost: {}/{} ({:.1}%)", udp.lost(), udp.sent, udp.loss_percent()))
+                .bold()
+                .fg(if udp.lost() > 0 { Color::Red } else { Color::Reset })
+                .centered(),
+            Line::from(format!("Lost up: {} | down: {}", udp.upstream_lost, udp.downstream_lost))
+                .centered(),
+            Line::from(format!("Reordered: {} | Duplicates: {}", udp.reordered, udp.duplicates))
+                .fg(if udp.reordered > 0 || udp.duplicates > 0 { Color::Yellow } else { Color::Reset })
+                .centered(),
+        ])
+    }
+}
+
+impl Widget for &UdpComponent {
+    fn render(self, area: ratatui::layout::Rect, buf: &mut ratatui::buffer::Buffer) {
+        let udp_res = match &self.udp_measurement {
+            Ok(udp) => UdpComponent::lines(udp),
+            // A failed test shows why instead of a clean result
+            Err(e) => Text::from(Line::from(format!("UDP test failed: {}", e)).bold().red()),
+        };
+
+        let block = Block::bordered()
+            .border_style(Style::default().fg(if self.active { Color::Green } else { Color::Red }))
+            .title("UDP Jitter & Loss");
+
+        let paragraph = ratatui::widgets::Paragraph::new(udp_res)
+            .block(block)
+            .alignment(ratatui::layout::Alignment::Center)
+            .wrap(ratatui::widgets::Wrap { trim: true });
+
+        paragraph.render(area, buf);
+    }
+}
@@ -0,0 +1,171 @@
+use std::{collections::HashSet, io::Error, time::{Duration, Instant}};
+use tokio::{net::{lookup_host, UdpSocket}, time::timeout_at};
+
+use crate::http_tester::HttpLatencyMeasurement;
+
+/// Marks the packets of this test so the responder ignores anything else.
+pub const UDP_MAGIC: &[u8; 4] = b"STUI";
+/// Magic, sequence number, client send time, responder receive time and
+/// responder packet count.
+pub const UDP_HEADER_SIZE: usize = 28;
+/// Same payload size and rate as a G.711 voice call.
+const DEFAULT_PACKET_SIZE: usize = 160;
+const DEFAULT_PACKET_COUNT: usize = 200;
+const DEFAULT_PACKET_INTERVAL: Duration = Duration::from_millis(20);
+/// How long echoes are still waited for after the last packet is sent.
+const ECHO_LINGER: Duration = Duration::from_secs(1);
+
+/// Result of a UDP echo test. Round trip loss is split by direction using
+/// the packet count the responder puts in every echo.
+#[derive(Debug, Default, Clone)]
+pub struct UdpMeasurement {
+    pub sent: usize,
+    pub received: usize,
+    pub upstream_lost: usize,
+    pub downstream_lost: usize,
+    pub reordered: usize,
+    pub duplicates: usize,
+    pub upstream_jitter: f64, // ms, RFC 3550 interarrival jitter at the responder
+    pub downstream_jitter: f64, // ms, RFC 3550 interarrival jitter of the echoes
+    pub rtt: HttpLatencyMeasurement,
+}
+
+impl UdpMeasurement {
+    pub fn lost(&self) -> usize {
+        self.sent.saturating_sub(self.received)
+    }
+
+    pub fn loss_percent(&self) -> f64 {
+        if self.sent == 0 {
+            0.0
+        } else {
+            self.lost() as f64 / self.sent as f64 * 100.0
+        }
+    }
+}
+
+/// Running RFC 3550 jitter estimate over the transit times of a stream of
+/// packets; a constant clock offset in the transit times cancels out.
+#[derive(Debug, Default)]
+struct JitterEstimate {
+    last_transit: Option<f64>,
+    jitter: f64,
+}
+
+impl JitterEstimate {
+    fn record(&mut self, transit: f64) {
+        if let Some(last) = self.last_transit {
+            self.jitter += ((transit - last).abs() - self.jitter) / 16.0;
+        }
+        self.last_transit = Some(transit);
+    }
+}
+
+/// Sends a fixed rate stream of numbered, timestamped packets to a UDP echo
+/// responder such as the one `speedtest-tui serve` runs, and works out loss,
+/// reordering, duplicates and jitter from the echoes.
+#[derive(Debug, Clone)]
+pub struct UdpTester {
+    pub host: String, // host:port
+    pub packet_size: usize,
+    pub packet_count: usize,
+    pub packet_interval: Duration,
+}
+
+impl UdpTester {
+    pub fn new(host: &str) -> Self {
+        UdpTester {
+            host: host.to_string(),
+            packet_size: DEFAULT_PACKET_SIZE,
+            packet_count: DEFAULT_PACKET_COUNT,
+            packet_interval: DEFAULT_PACKET_INTERVAL,
+        }
+    }
+
+    fn micros_since(start: Instant) -> u64 {
+        start.elapsed().as_micros() as u64
+    }
+
+    pub async fn measure(&self) -> Result<UdpMeasurement, Error> {
+        let address = lookup_host(self.host.as_str()).await?
+            .next()
+            .ok_or_else(|| Error::other(format!("No address found for {}", self.host)))?;
+        let local = if address.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(address).await?;
+
+        let start = Instant::now();
+        let deadline = start + self.packet_interval * self.packet_count as u32 + ECHO_LINGER;
+        let send = async {
+            let mut packet = vec![0u8; self.packet_size.max(UDP_HEADER_SIZE)];
+            packet[..4].copy_from_slice(UDP_MAGIC);
+            let mut ticker = tokio::time::interval(self.packet_interval);
+            let mut last_error = None;
+            let mut accepted = 0;
+            for seq in 0..self.packet_count as u32 {
+                ticker.tick().await;
+                packet[4..8].copy_from_slice(&seq.to_be_bytes());
+                packet[8..16].copy_from_slice(&Self::micros_since(start).to_be_bytes());
+                // A packet the kernel refuses, e.g. after an ICMP port unreachable,
+                // counts as lost like one the network drops
+                match socket.send(&packet).await {
+                    Ok(_) => accepted += 1,
+                    Err(e) => last_error = Some(e),
+                }
+            }
+            match last_error {
+                Some(e) if accepted == 0 => Err(e),
+                _ => Ok(()),
+            }
+        };
+        let receive = async {
+            let mut buf = vec![0u8; self.packet_size.max(UDP_HEADER_SIZE) + 64];
+            let mut seen = HashSet::new();
+            let mut highest: Option<u32> = None;
+            let mut rtts = Vec::new();
+            let mut measurement = UdpMeasurement::default();
+            let mut responder_count = 0;
+            let mut upstream = JitterEstimate::default();
+            let mut downstream = JitterEstimate::default();
+            while let Ok(received) = timeout_at(deadline.into(), socket.recv(&mut buf)).await {
+                // Errors such as ICMP port unreachable just mean no echo came back
+                let len = match received {
+                    Ok(len) => len,
+                    Err(_) => continue,
+                };
+                if len < UDP_HEADER_SIZE || &buf[..4] != UDP_MAGIC {
+                    continue;
+                }
+                let now = Self::micros_since(start);
+                let seq = u32::from_be_bytes(buf[4..8].try_into().unwrap_or_default());
+                let sent_at = u64::from_be_bytes(buf[8..16].try_into().unwrap_or_default());
+                let responder_at = u64::from_be_bytes(buf[16..24].try_into().unwrap_or_default());
+                let count = u32::from_be_bytes(buf[24..28].try_into().unwrap_or_default());
+                if !seen.insert(seq) {
+                    measurement.duplicates += 1;
+                    continue;
+                }
+                match highest {
+                    Some(max) if seq < max => measurement.reordered += 1,
+                    _ => highest = Some(seq),
+                }
+                responder_count = responder_count.max(count as usize);
+                rtts.push(now.saturating_sub(sent_at) as f64 / 1000.0);
+                upstream.record(responder_at as f64 / 1000.0 - sent_at as f64 / 1000.0);
+                downstream.record(now as f64 / 1000.0 - responder_at as f64 / 1000.0);
+            }
+            measurement.received = rtts.len();
+            measurement.upstream_jitter = upstream.jitter;
+            measurement.downstream_jitter = downstream.jitter;
+            (measurement, rtts, responder_count)
+        };
+
+        let (sent, (mut measurement, rtts, responder_count)) = tokio::join!(send, receive);
+        sent?;
+        measurement.sent = self.packet_count;
+        measurement.upstream_lost = self.packet_count.saturating_sub(responder_count);
+        measurement.downstream_lost = responder_count.saturating_sub(measurement.received);
+        measurement.rtt = HttpLatencyMeasurement::from_samples(rtts, measurement.lost());
+        Ok(measurement)
+    }
+}
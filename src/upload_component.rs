@@ -1,30 +1,63 @@
+use std::time::Duration;
+
 use ratatui::{style::{Color, Style, Stylize}, text::{Line, Text}, widgets::{Block, Paragraph, Widget}};
 
-use crate::http_tester::HttpUploadMeasurement;
+use crate::http_tester::{HttpUploadMeasurement, HttpThroughputSample};
 
-#[derive(Default, Clone)]
+#[derive(Clone)]
 pub struct UploadComponent {
-    upload_measurement: HttpUploadMeasurement,
+    upload_measurement: Result<HttpUploadMeasurement, String>,
+    progress: Option<HttpThroughputSample>,
     active: bool,
 }
 
+impl Default for UploadComponent {
+    fn default() -> Self {
+        UploadComponent {
+            upload_measurement: Ok(HttpUploadMeasurement::default()),
+            progress: None,
+            active: false,
+        }
+    }
+}
+
 impl UploadComponent {
-    pub fn set_upload_measurement(&mut self, measurement: HttpUploadMeasurement) {
+    pub fn set_upload_measurement(&mut self, measurement: Result<HttpUploadMeasurement, String>) {
         self.upload_measurement = measurement;
     }
+    pub fn set_progress(&mut self, progress: Option<HttpThroughputSample>) {
+        self.progress = progress;
+    }
     pub fn set_active(&mut self, active: bool) {
         self.active = active;
     }
+
+    fn lines(bits: u64, duration: Duration, speed: f64, raw_speed: f64) -> Text<'static> {
+        Text::from(vec![
+            Line::from(format!("Uploaded data: {} MB", bits / (1024 * 1024 * 8)).green()),
+            Line::from(format!("Duration: {:.2} seconds", duration.as_secs_f64()).red()),
+            Line::from(format!("Speed: {:.2} Mbps (raw {:.2} Mbps)", speed / (1024 * 1024) as f64, raw_speed / (1024 * 1024) as f64).blue()),
+        ])
+    }
 }
 
 impl Widget for &UploadComponent {
     fn render(self, area: ratatui::prelude::Rect, buf: &mut ratatui::prelude::Buffer) {
         let title = Line::from("Upload Speed").bold();
-        let content = Text::from(vec![
-            Line::from(format!("Uploaded data: {} MB", self.upload_measurement.bits / (1024 * 1024 * 8)).green()),
-            Line::from(format!("Duration: {:.2} seconds", self.upload_measurement.duration.as_secs_f64()).red()),
-            Line::from(format!("Speed: {:.2} Mbps", self.upload_measurement.speed / (1024 * 1024) as f64).blue()),
-        ]);
+        // While the phase runs show the live sample instead of the last result
+        let content = match (&self.progress, self.active, &self.upload_measurement) {
+            (Some(progress), true, _) => UploadComponent::lines(progress.bits, progress.elapsed, progress.speed, progress.speed),
+            (_, _, Ok(measurement)) => {
+                let mut text = UploadComponent::lines(measurement.bits, measurement.duration, measurement.speed, measurement.raw_speed);
+                // Failed requests were retried, but the streams lost time on them
+                if measurement.failed_requests > 0 {
+                    text.push_line(Line::from(format!("{} failed requests retried", measurement.failed_requests)).yellow());
+                }
+                text
+            }
+            // A failed phase shows why instead of a speed of zero
+            (_, _, Err(e)) => Text::from(Line::from(format!("Upload failed: {}", e)).bold().red()),
+        };
 
         let block = Block::bordered()
             .border_style(Style::default().fg(if self.active {Color::Green } else { Color::Red }))